@@ -0,0 +1,64 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for checking which FDs a child actually receives, enabled by the `testing` feature.
+//!
+//! These only work on Linux and Android, as they rely on `/proc/self/fd`.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::process::Command;
+use std::str;
+
+/// The script run by [`fd_listing_command`]. The directory FD used to expand the glob is closed
+/// again by the time each entry is checked, so only FDs which the shell inherited are printed.
+const LIST_FDS_SCRIPT: &str =
+    r#"for fd in /proc/self/fd/*; do [ -e "$fd" ] && echo "${fd##*/}"; done; exit 0"#;
+
+/// Creates a command which prints the FDs which are open in the child when it starts, one per
+/// line, for use with [`passed_fds`].
+///
+/// Add the mappings to be checked to this command, in place of the program which will eventually
+/// receive them.
+pub fn fd_listing_command() -> Command {
+    let mut command = Command::new("/bin/sh");
+    command.arg("-c").arg(LIST_FDS_SCRIPT);
+    command
+}
+
+/// Runs a command created by [`fd_listing_command`], and returns the FDs which were open in the
+/// child in ascending order, including stdin, stdout and stderr.
+///
+/// This makes it easy to assert that a child gets exactly the FDs it is meant to, and that nothing
+/// else leaks into it because `FD_CLOEXEC` wasn't set.
+pub fn passed_fds(command: &mut Command) -> io::Result<Vec<RawFd>> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "FD listing command failed with {}",
+            output.status
+        )));
+    }
+    let stdout = str::from_utf8(&output.stdout)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut fds = stdout
+        .lines()
+        .map(|line| {
+            line.parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect::<io::Result<Vec<RawFd>>>()?;
+    fds.sort_unstable();
+    Ok(fds)
+}
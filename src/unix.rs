@@ -0,0 +1,6797 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The implementation of the crate, which only builds on Unix. Everything public here is
+//! re-exported from the crate root.
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use nix::fcntl::{open, SealFlag};
+#[cfg(target_os = "linux")]
+use nix::libc::c_uint;
+use nix::libc::{self, off_t};
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use nix::sys::eventfd::eventfd;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use nix::sys::eventfd::EfdFlags;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
+use nix::sys::stat::fstat;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use nix::sys::stat::Mode;
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+use nix::unistd::pipe;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use nix::unistd::pipe2;
+use nix::unistd::{close, dup2, lseek, Whence};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::cmp::max;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::ffi::CStr;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::io::Write;
+use std::iter::FromIterator;
+use std::mem::size_of;
+use std::num::NonZeroUsize;
+use std::ops::{Deref, DerefMut, Range, RangeInclusive};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+/// A mapping from a file descriptor in the parent to a file descriptor in the child, to be applied
+/// when spawning a child process.
+///
+/// The parent_fd must be kept open until after the child is spawned.
+///
+/// The child_fd never has `FD_CLOEXEC` set, whatever the flags of the parent_fd, as otherwise it
+/// would be closed by the very exec which starts the child program. There is deliberately no option
+/// to copy the parent_fd's flag instead: the standard library and tokio open almost every FD with
+/// `FD_CLOEXEC` set, so copying it would close nearly every mapped FD at exec. If the child
+/// shouldn't pass the FD on to any processes it spawns itself, it needs to set `FD_CLOEXEC` on it
+/// once it has started. If the FD is only needed by a later `pre_exec` hook of your own, that hook
+/// can set `FD_CLOEXEC` on the child_fd once it is done with it.
+/// The flag is only ever cleared in the child's own FD table after forking, never on the parent_fd
+/// itself, so children may be spawned concurrently from several threads with the same parent_fd.
+///
+/// Several mappings may have the same parent_fd, to pass the same FD to the child at several child
+/// FDs. They then all refer to the same open file description, so share its offset and status
+/// flags like any duplicated FDs.
+///
+/// By default `O_NONBLOCK` is cleared on the child_fd, as tokio and other async runtimes put their
+/// FDs into non-blocking mode, and few programs expect to inherit an FD like that. See
+/// [`BlockingMode`] for the alternatives, and note that this changes the flag for the parent too.
+/// The child_fd may likewise be moved to a given offset with [`seek`](Self::seek).
+///
+/// The child also inherits the parent_fd itself unless it has `FD_CLOEXEC` set, as is the case for
+/// FDs opened by Rust but not for stdio or FDs this process inherited. Set
+/// [`move_source`](Self::move_source) to close it in the child once it has been duplicated, or
+/// [`keep_source`](Self::keep_source) to make sure the child inherits it at both numbers.
+///
+/// Mapping onto stdin, stdout or stderr overrides the command's stdio configuration for that FD, so
+/// the checked methods such as [`CommandFdExt::try_fd_mappings`] reject it unless the mapping's
+/// [`stdio_policy`](Self::stdio_policy) allows it, as described for [`StdioPolicy`].
+///
+/// A mapping may also be given a [`label`](Self::label) saying what it is for, which is included
+/// in errors about it and in the mappings of a [`PreparedFdMappings`].
+///
+/// With the `serde` feature enabled this is (de)serialized as an object with `parent_fd`,
+/// `child_fd`, `blocking_mode`, `seek`, `move_source`, `keep_source`, `stdio_policy` and `label`
+/// fields, the first two of which
+/// may also be given as `old_fd` and `new_fd` respectively. The others may be left out, to use the
+/// defaults.
+///
+/// Mappings are ordered by parent_fd, then child_fd, then the rest of their fields in turn. The
+/// order in which a set of mappings is given makes no difference to how they are applied, as they
+/// are sorted into this order before working out how to apply them.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct FdMapping {
+    #[cfg_attr(feature = "serde", serde(alias = "old_fd"))]
+    pub parent_fd: RawFd,
+    #[cfg_attr(feature = "serde", serde(alias = "new_fd"))]
+    pub child_fd: RawFd,
+    /// Whether the child_fd is put into blocking or non-blocking mode before exec.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "BlockingMode::is_default")
+    )]
+    pub blocking_mode: BlockingMode,
+    /// Where to move the offset of the child_fd to before exec, if anywhere.
+    ///
+    /// The offset belongs to the open file description, so this moves it for the parent_fd and any
+    /// other duplicates too. It is moved each time the command is spawned.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub seek: Option<SeekPosition>,
+    /// Whether to close the parent_fd in the child once every mapping has been applied, so that
+    /// the child only has it as the child_fd.
+    ///
+    /// The parent_fd is left open if it is also the child_fd of some mapping in the same set, as it
+    /// then holds that mapping's FD by the time it would be closed.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "std::ops::Not::not")
+    )]
+    pub move_source: bool,
+    /// Whether to also clear `FD_CLOEXEC` on the parent_fd in the child, so that the child inherits
+    /// the same open file description at both the parent_fd and the child_fd.
+    ///
+    /// This is for protocols which expect an FD at a conventional number as well as wherever it is
+    /// mapped to. It is the opposite of [`move_source`](Self::move_source), and wins if both are
+    /// set. Planning fails with a collision at the parent_fd if another mapping in the same set
+    /// replaces it, as the child can't have both there.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "std::ops::Not::not")
+    )]
+    pub keep_source: bool,
+    /// Whether the checked methods allow the child_fd to be stdin, stdout or stderr.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "StdioPolicy::is_default")
+    )]
+    pub stdio_policy: StdioPolicy,
+    /// A description of what the mapping is for, such as `"metrics-socket"`, for diagnostics.
+    ///
+    /// This is only ever used in the parent, so it makes no difference to how the mapping is
+    /// applied.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub label: Option<String>,
+}
+
+/// An offset to move an FD passed to a child to, for [`FdMapping::seek`].
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum SeekPosition {
+    /// The given number of bytes from the start of the file, so `Start(0)` rewinds it.
+    Start(u64),
+    /// The given number of bytes from the end of the file, so `End(0)` is where appending writes
+    /// would go.
+    End(i64),
+}
+
+/// What to do with the `O_NONBLOCK` flag of an FD passed to a child.
+///
+/// The flag belongs to the open file description rather than to the FD, so it is shared by the
+/// parent_fd, the child_fd and any other duplicates of them. Changing it in the child therefore
+/// changes it for the parent too, which matters if the parent keeps using the FD, for example
+/// with an async runtime which relies on it being non-blocking. If several mappings refer to the
+/// same open file description with different modes, it is unspecified which of them wins.
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum BlockingMode {
+    /// Clear `O_NONBLOCK`, so the child gets a blocking FD.
+    #[default]
+    Blocking,
+    /// Set `O_NONBLOCK`, so the child gets a non-blocking FD.
+    Nonblocking,
+    /// Leave `O_NONBLOCK` as the parent_fd had it.
+    Inherit,
+}
+
+impl BlockingMode {
+    /// Whether this is the default mode, which is left out when serializing a mapping.
+    #[cfg(feature = "serde")]
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// What the checked methods such as [`CommandFdExt::try_fd_mappings`] do with a mapping whose
+/// child_fd is stdin, stdout or stderr, other than one mapping the FD to itself.
+///
+/// Such a mapping replaces whatever the command's [`stdin`](std::process::Command::stdin),
+/// [`stdout`](std::process::Command::stdout) or [`stderr`](std::process::Command::stderr)
+/// configuration set up for the FD, as the mappings are applied after it, so by default it is
+/// rejected in case that wasn't intended.
+///
+/// The policy is checked by the methods which take whole mappings, such as
+/// [`CommandFdExt::try_fd_mappings`] and [`PreparedFdMappings::new`], and those built on them,
+/// such as [`CommandFdExt::compact_fd_mappings`]. [`CommandFdExt::fd_mappings`] only checks for
+/// collisions, so allows it regardless. The helpers which take an FD along with the child FD to
+/// pass it at, such as [`CommandFdExt::cloned_fd_mappings`] or [`CommandFdExt::memfd_to_child`],
+/// treat that as an explicit request, so allow it too.
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum StdioPolicy {
+    /// Reject the mapping with [`FdMappingError::StdioTarget`].
+    #[default]
+    Error,
+    /// Allow the mapping.
+    Allow,
+    /// Allow the mapping, but log a warning about it when the `tracing` feature is enabled.
+    Warn,
+}
+
+impl StdioPolicy {
+    /// Whether this is the default policy, which is left out when serializing a mapping.
+    #[cfg(feature = "serde")]
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl FdMapping {
+    /// Creates a mapping from `parent_fd` in the parent to `child_fd` in the child.
+    pub fn new(parent_fd: RawFd, child_fd: RawFd) -> Self {
+        Self {
+            parent_fd,
+            child_fd,
+            blocking_mode: BlockingMode::default(),
+            seek: None,
+            move_source: false,
+            keep_source: false,
+            stdio_policy: StdioPolicy::default(),
+            label: None,
+        }
+    }
+
+    /// Sets what to do with the `O_NONBLOCK` flag of the child_fd.
+    pub fn with_blocking_mode(mut self, blocking_mode: BlockingMode) -> Self {
+        self.blocking_mode = blocking_mode;
+        self
+    }
+
+    /// Sets an offset to move the child_fd to before exec, which also moves the parent's offset.
+    pub fn with_seek(mut self, position: SeekPosition) -> Self {
+        self.seek = Some(position);
+        self
+    }
+
+    /// Sets whether to close the parent_fd in the child once it has been duplicated to the
+    /// child_fd.
+    pub fn with_move_source(mut self, move_source: bool) -> Self {
+        self.move_source = move_source;
+        self
+    }
+
+    /// Sets whether the child also inherits the parent_fd at its own number.
+    pub fn with_keep_source(mut self, keep_source: bool) -> Self {
+        self.keep_source = keep_source;
+        self
+    }
+
+    /// Sets whether the checked methods allow the child_fd to be stdin, stdout or stderr,
+    /// overriding the command's stdio configuration.
+    pub fn with_stdio_policy(mut self, stdio_policy: StdioPolicy) -> Self {
+        self.stdio_policy = stdio_policy;
+        self
+    }
+
+    /// Sets a description of what the mapping is for, to include in errors about it.
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Like [`new`](Self::new), but checks that neither FD is negative, as negative FDs are never
+    /// valid.
+    ///
+    /// Mappings are checked for this anyway by [`CommandFdExt::try_fd_mappings`] and the other
+    /// checked methods, but this reports it where the mapping is created.
+    pub fn try_new(parent_fd: RawFd, child_fd: RawFd) -> Result<Self, FdMappingError> {
+        let mapping = Self::new(parent_fd, child_fd);
+        validate_fds(std::slice::from_ref(&mapping))?;
+        Ok(mapping)
+    }
+
+    /// Creates a mapping from the FD of `source` in the parent to `child_fd` in the child.
+    ///
+    /// This only borrows the FD number, so `source` must still be kept open until after the child
+    /// is spawned.
+    pub fn from_source(source: &impl AsRawFd, child_fd: RawFd) -> Self {
+        Self::new(source.as_raw_fd(), child_fd)
+    }
+}
+
+impl From<(RawFd, RawFd)> for FdMapping {
+    fn from((parent_fd, child_fd): (RawFd, RawFd)) -> Self {
+        Self::new(parent_fd, child_fd)
+    }
+}
+
+/// A collection of [`FdMapping`]s to be applied together, which can be built up bit by bit and
+/// validated before being added to a command.
+///
+/// Anything which takes an `impl Into<FdMappings>` also accepts a `Vec<FdMapping>` or an array of
+/// them. With the `serde` feature enabled this is (de)serialized as a sequence of mappings.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize), serde(transparent))]
+pub struct FdMappings {
+    mappings: Vec<FdMapping>,
+}
+
+impl FdMappings {
+    /// Creates an empty collection of mappings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty collection with room for at least `capacity` mappings, to avoid reallocating
+    /// while adding a large number of them.
+    ///
+    /// Collecting or extending from an iterator already reserves room for as many mappings as the
+    /// iterator says it has.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            mappings: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Reserves room for at least `additional` more mappings.
+    pub fn reserve(&mut self, additional: usize) {
+        self.mappings.reserve(additional);
+    }
+
+    /// Returns the number of mappings the collection can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.mappings.capacity()
+    }
+
+    /// Adds a mapping to the collection.
+    pub fn push(&mut self, mapping: FdMapping) {
+        self.mappings.push(mapping);
+    }
+
+    /// Like [`push`](Self::push), but returns the collection so that calls can be chained, such as
+    /// to add mappings conditionally while building up the set for a command.
+    ///
+    /// Collisions are only checked once the whole set is validated or added to a command, so that
+    /// the mappings are all checked and applied together. Calling the [`CommandFdExt`] methods once
+    /// per mapping instead would apply each one separately.
+    pub fn add_fd_mapping(&mut self, mapping: impl Into<FdMapping>) -> &mut Self {
+        self.push(mapping.into());
+        self
+    }
+
+    /// Checks that no two mappings in the collection have the same child FD.
+    pub fn validate(&self) -> Result<(), FdMappingCollision> {
+        match find_duplicate_child_fd(&self.mappings) {
+            Some(child_fd) => Err(FdMappingCollision { child_fd }),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds all of the given mappings to the collection, as long as the result has no two mappings
+    /// with the same child FD. Otherwise the collection is left unchanged, and the first such child
+    /// FD is reported.
+    pub fn merge(&mut self, other: impl Into<FdMappings>) -> Result<(), FdMappingCollision> {
+        let mut merged = self.clone();
+        merged.extend(other.into());
+        merged.validate()?;
+        *self = merged;
+        Ok(())
+    }
+
+    /// Adds all of the given mappings to the collection, moving any whose child FD is already taken
+    /// to the lowest child FD at or above `first_child_fd` which no mapping in either collection
+    /// uses.
+    ///
+    /// Mappings which are already in the collection, and the first of the given mappings for each
+    /// child FD, keep their child FDs. Returns the mappings which were moved, so that the child can
+    /// be told where to find them.
+    pub fn merge_renumbering(
+        &mut self,
+        other: impl Into<FdMappings>,
+        first_child_fd: RawFd,
+    ) -> Vec<RenumberedMapping> {
+        let other = other.into();
+        let mut taken = sorted_child_fds(&self.mappings);
+        // The child FDs to keep for each of the other mappings, or None for those to move.
+        let requested: Vec<(RawFd, Option<RawFd>)> = other
+            .iter()
+            .map(|mapping| match taken.binary_search(&mapping.child_fd) {
+                Ok(_) => (mapping.parent_fd, None),
+                Err(position) => {
+                    taken.insert(position, mapping.child_fd);
+                    (mapping.parent_fd, Some(mapping.child_fd))
+                }
+            })
+            .collect();
+        // Include the mappings already in the collection, so that nothing is moved on to them.
+        let all: Vec<(RawFd, Option<RawFd>)> = self
+            .mappings
+            .iter()
+            .map(|mapping| (mapping.parent_fd, Some(mapping.child_fd)))
+            .chain(requested.iter().copied())
+            .collect();
+        let assigned = assign_child_fds(&all, first_child_fd);
+
+        let mut renumbered = Vec::new();
+        for ((mapping, (_, kept_fd)), assigned) in other
+            .into_iter()
+            .zip(requested)
+            .zip(&assigned[self.mappings.len()..])
+        {
+            let requested_child_fd = mapping.child_fd;
+            let mapping = FdMapping {
+                child_fd: assigned.child_fd,
+                ..mapping
+            };
+            if kept_fd.is_none() {
+                renumbered.push(RenumberedMapping {
+                    requested_child_fd,
+                    mapping: mapping.clone(),
+                });
+            }
+            self.mappings.push(mapping);
+        }
+        renumbered
+    }
+
+    /// Checks the mappings against the FDs which are currently open in this process, to help
+    /// diagnose why a child didn't get the FDs it was meant to, without having to spawn it.
+    ///
+    /// Returns every issue found, in the order of the mappings: parent FDs which aren't open, child
+    /// FDs which more than one mapping targets, and child FDs which are open in this process without
+    /// `FD_CLOEXEC`, so the child would otherwise have inherited them. The last aren't necessarily
+    /// mistakes, but mean that the child won't get whatever is open there now. Stdin, stdout and
+    /// stderr are set up separately for each command, so mapping to them isn't reported.
+    pub fn validate_against_open_fds(&self) -> io::Result<Vec<OpenFdIssue>> {
+        let mut issues = Vec::new();
+        for (index, mapping) in self.mappings.iter().enumerate() {
+            if fd_flags(mapping.parent_fd)?.is_none() {
+                issues.push(OpenFdIssue::ParentFdNotOpen(mapping.parent_fd));
+            }
+            let first_for_child_fd = !self.mappings[..index]
+                .iter()
+                .any(|earlier| earlier.child_fd == mapping.child_fd);
+            if !first_for_child_fd {
+                continue;
+            }
+            let targets = self
+                .mappings
+                .iter()
+                .filter(|other| other.child_fd == mapping.child_fd)
+                .count();
+            if targets > 1 {
+                issues.push(OpenFdIssue::DuplicateTarget(mapping.child_fd));
+            }
+            if mapping.child_fd > 2 && mapping.child_fd != mapping.parent_fd {
+                if let Some(flags) = fd_flags(mapping.child_fd)? {
+                    if !flags.contains(FdFlag::FD_CLOEXEC) {
+                        issues.push(OpenFdIssue::ReplacesInheritedFd(mapping.child_fd));
+                    }
+                }
+            }
+        }
+        Ok(issues)
+    }
+}
+
+/// A mapping which [`FdMappings::merge_renumbering`] moved to a different child FD, because the one
+/// it asked for was already taken.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RenumberedMapping {
+    /// The child FD which the mapping asked for.
+    pub requested_child_fd: RawFd,
+    /// The mapping as it was added, with its new child FD.
+    pub mapping: FdMapping,
+}
+
+/// An issue with a set of mappings found by [`FdMappings::validate_against_open_fds`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OpenFdIssue {
+    /// Nothing is open at this parent FD.
+    ParentFdNotOpen(RawFd),
+    /// Two or more mappings target this child FD.
+    DuplicateTarget(RawFd),
+    /// This child FD is open without `FD_CLOEXEC` in this process, so would otherwise have been
+    /// inherited by the child, but is replaced by a mapping.
+    ReplacesInheritedFd(RawFd),
+}
+
+/// Gives access to the mappings in the order they were added, like a slice.
+impl Deref for FdMappings {
+    type Target = [FdMapping];
+
+    fn deref(&self) -> &[FdMapping] {
+        &self.mappings
+    }
+}
+
+impl From<Vec<FdMapping>> for FdMappings {
+    fn from(mappings: Vec<FdMapping>) -> Self {
+        Self { mappings }
+    }
+}
+
+impl<const N: usize> From<[FdMapping; N]> for FdMappings {
+    fn from(mappings: [FdMapping; N]) -> Self {
+        Self {
+            mappings: mappings.into(),
+        }
+    }
+}
+
+impl From<FdMappings> for Vec<FdMapping> {
+    fn from(mappings: FdMappings) -> Self {
+        mappings.mappings
+    }
+}
+
+impl FromIterator<FdMapping> for FdMappings {
+    fn from_iter<I: IntoIterator<Item = FdMapping>>(iter: I) -> Self {
+        Self {
+            mappings: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl Extend<FdMapping> for FdMappings {
+    fn extend<I: IntoIterator<Item = FdMapping>>(&mut self, iter: I) {
+        self.mappings.extend(iter);
+    }
+}
+
+impl IntoIterator for FdMappings {
+    type Item = FdMapping;
+    type IntoIter = std::vec::IntoIter<FdMapping>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.mappings.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a FdMappings {
+    type Item = &'a FdMapping;
+    type IntoIter = std::slice::Iter<'a, FdMapping>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.mappings.iter()
+    }
+}
+
+/// Error setting up FD mappings, because there were two or more mappings for the same child FD.
+#[derive(Copy, Clone, Debug, Eq, Error, PartialEq)]
+#[error("Two or more mappings for the same child FD {child_fd}")]
+pub struct FdMappingCollision {
+    /// The child FD which more than one mapping targets.
+    pub child_fd: RawFd,
+}
+
+/// Error validating or applying FD mappings.
+#[derive(Debug, Error)]
+pub enum FdMappingError {
+    /// Two or more mappings were given for the same child FD.
+    #[error("Two or more mappings for the same child FD {0}")]
+    DuplicateTarget(RawFd),
+    /// An FD in a mapping was negative, or was not open when it was needed.
+    #[error("Invalid or closed FD {0}")]
+    BadFd(RawFd),
+    /// Applying the mappings would need an FD which is too high for the `RLIMIT_NOFILE` soft limit,
+    /// either as a child FD or as a temporary FD needed to resolve conflicts between mappings.
+    #[error("Mappings need FD {fd}, but the RLIMIT_NOFILE soft limit is {limit}")]
+    FdTooHigh { fd: RawFd, limit: u64 },
+    /// Applying the mappings would need an FD which is too high even for the `RLIMIT_NOFILE` hard
+    /// limit, so the soft limit can't be raised far enough to allow it.
+    #[error("Mappings need FD {fd}, but the RLIMIT_NOFILE hard limit is {limit}")]
+    HardFdLimit { fd: RawFd, limit: u64 },
+    /// The FD given for temporary FDs to start from isn't higher than every child FD, so temporary
+    /// FDs might take a child FD before it is mapped.
+    #[error("Temporary FDs can't start at {fd}, as it isn't above child FD {child_fd}")]
+    TemporaryFdTooLow { fd: RawFd, child_fd: RawFd },
+    /// A mapping would replace stdin, stdout or stderr, but its [`StdioPolicy`] doesn't allow it.
+    #[error("Mapping to stdio FD {0} would override the command's stdio configuration")]
+    StdioTarget(RawFd),
+    /// There are more mappings than FDs in the window given to
+    /// [`CommandFdExt::clamped_fd_mappings`].
+    #[error("{count} mappings don't fit in the FDs from {start} to below {end}")]
+    WindowTooSmall {
+        count: usize,
+        start: RawFd,
+        end: RawFd,
+    },
+    /// A mapping's [`seek`](FdMapping::seek) offset doesn't fit in the platform's `off_t`.
+    #[error("Seek position {position:?} for child FD {child_fd} is out of range")]
+    SeekOutOfRange {
+        child_fd: RawFd,
+        position: SeekPosition,
+    },
+    /// A system call failed while applying the mappings.
+    #[error("Error applying FD mappings: {0}")]
+    Syscall(#[from] io::Error),
+    /// Applying the mapping from `parent_fd` to `child_fd` failed in the child, as reported by
+    /// [`MappingDiagnostics`]. The label is that of the mapping, if it has one.
+    #[error(
+        "Failed to map {}: {source}",
+        describe_mapping(*.parent_fd, *.child_fd, .label.as_deref())
+    )]
+    MappingFailed {
+        parent_fd: RawFd,
+        child_fd: RawFd,
+        label: Option<String>,
+        source: io::Error,
+    },
+}
+
+/// Describes a mapping for an error message, by its label if it has one as well as its FDs.
+fn describe_mapping(parent_fd: RawFd, child_fd: RawFd, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("'{}' (FD {} to {})", label, parent_fd, child_fd),
+        None => format!("FD {} to {}", parent_fd, child_fd),
+    }
+}
+
+impl FdMappingError {
+    /// Converts the error to an [`io::Error`] without allocating, so that it can be returned from a
+    /// `pre_exec` hook. Only the OS error code makes it back to the parent process anyway.
+    fn into_raw_os_error(self) -> io::Error {
+        match self {
+            Self::Syscall(e) | Self::MappingFailed { source: e, .. } => e,
+            error => io::Error::from_raw_os_error(error.errno()),
+        }
+    }
+
+    /// Returns the OS error code which best describes the error.
+    fn errno(&self) -> i32 {
+        match self {
+            Self::DuplicateTarget(_) | Self::TemporaryFdTooLow { .. } | Self::StdioTarget(_) => {
+                Errno::EINVAL as i32
+            }
+            Self::BadFd(_) => Errno::EBADF as i32,
+            Self::SeekOutOfRange { .. } => Errno::EOVERFLOW as i32,
+            Self::FdTooHigh { .. } | Self::HardFdLimit { .. } | Self::WindowTooSmall { .. } => {
+                Errno::EMFILE as i32
+            }
+            Self::Syscall(e) | Self::MappingFailed { source: e, .. } => {
+                e.raw_os_error().unwrap_or(Errno::EIO as i32)
+            }
+        }
+    }
+
+    fn from_errno(errno: Errno, fd: RawFd) -> Self {
+        match errno {
+            Errno::EBADF => Self::BadFd(fd),
+            errno => Self::Syscall(errno.into()),
+        }
+    }
+}
+
+impl From<FdMappingCollision> for FdMappingError {
+    fn from(collision: FdMappingCollision) -> Self {
+        Self::DuplicateTarget(collision.child_fd)
+    }
+}
+
+impl From<FdMappingError> for io::Error {
+    fn from(error: FdMappingError) -> Self {
+        match error {
+            FdMappingError::Syscall(e) => e,
+            error => io::Error::new(io::ErrorKind::InvalidInput, error),
+        }
+    }
+}
+
+/// Extension to add file descriptor mappings to a [`tokio::process::Command`] or a
+/// [`std::process::Command`].
+///
+/// The mappings are applied by a `pre_exec` hook registered on the [`std::process::Command`] (or
+/// the one underlying the tokio command), so they compose with the rest of the builder methods and
+/// take effect when the command is spawned.
+///
+/// A command can't hold any state of its own for this library, so each call which adds mappings
+/// registers a separate hook, and the hooks are run one after another in the order the calls were
+/// made. Each set of mappings is validated on its own, and is applied to the FDs as the previous
+/// sets left them. So if a later call maps a child FD which an earlier call also mapped, the later
+/// mapping wins, and if a later call's parent FD is the child FD of an earlier mapping, it refers to
+/// what that mapping put there rather than what the parent has open at that number. To have
+/// mappings validated and applied together, pass them all in a single call.
+///
+/// Hooks registered directly with [`pre_exec`](CommandExt::pre_exec) are run in the same sequence,
+/// so a hook of your own, such as one calling `setsid` or `unshare` to set up namespaces, runs
+/// before the mappings added after it and after those added before it. Such a hook doesn't change
+/// the FD table, so the mappings work the same either way, but a hook which opens FDs for the
+/// mappings to pass on must be registered first, and used with
+/// [`deferred_fd_mappings`](Self::deferred_fd_mappings). To apply the mappings at a particular
+/// point within a hook of your own instead, use [`PreparedFdMappings`].
+///
+/// A command can be spawned again after spawning it fails, such as to retry once its program has
+/// been installed. The hooks only change the FD table of the forked child, so a failed spawn
+/// leaves the parent's FDs and the command's mappings as they were. FDs kept by the command, such
+/// as those passed to [`owned_fd_mappings`](Self::owned_fd_mappings), stay open until the command
+/// is dropped whether or not spawning succeeded, and those held by a [`PassedFds`] until it is
+/// dropped. So to retry, just spawn the command again. Adding the mappings again would register a
+/// second hook, applied to the FDs as the first one left them.
+///
+/// Settings which the standard library applies itself, such as
+/// [`process_group`](CommandExt::process_group), [`uid`](CommandExt::uid),
+/// [`gid`](CommandExt::gid) and [`current_dir`](std::process::Command::current_dir), always
+/// come before every `pre_exec` hook, whenever they were set. So the mappings always run in the
+/// child's new process group, for example. Tokio's `process_group` and `uid` do the same, as they
+/// set the underlying [`std::process::Command`]. To join a process group after the mappings
+/// instead, call `setpgid` from a hook of your own registered after them.
+///
+/// The hooks stay registered for the lifetime of the command, so mappings are sticky: each time
+/// the command is spawned, every set of mappings added to it so far is applied again, to the fresh
+/// FD table of the new child. The standard library offers no way to remove a `pre_exec` hook, so
+/// to be able to clear mappings from a command before a later spawn, add them with
+/// [`clearable_fd_mappings`](Self::clearable_fd_mappings), whose handle turns the hook into a
+/// no-op. To spawn children with different FDs in a loop, either do that for each child's
+/// mappings, or build a new command for each one. FDs owned by the command, such as those passed
+/// with [`owned_fd_mappings`](Self::owned_fd_mappings), likewise stay open in the parent for as
+/// long as the command exists, so every child gets a copy; use
+/// [`guarded_fd_mappings`](Self::guarded_fd_mappings) for FDs which are only meant for one child.
+///
+/// Stdin, stdout and stderr can be mapped like any other FDs, either as parent or child FDs, though
+/// the checked methods only allow mapping onto them if the mapping's [`StdioPolicy`] does. The
+/// `pre_exec` hooks run after the command's own [`stdin`](std::process::Command::stdin),
+/// [`stdout`](std::process::Command::stdout) and [`stderr`](std::process::Command::stderr)
+/// configuration has been applied in the child. So mapping to FD 0, 1 or 2 overrides that
+/// configuration, and mapping from one refers to whatever it was configured as, such as the pipe
+/// for [`Stdio::piped`](std::process::Stdio::piped). Within a single call the mappings are ordered so
+/// that each FD is read before it is overwritten, so for example mapping a file to stdout and the
+/// original stdout to stderr works as expected. The same goes for a tokio command, as its stdio
+/// configuration, including the pipes for [`Stdio::piped`](std::process::Stdio::piped), is applied
+/// by the underlying [`std::process::Command`] before any `pre_exec` hooks run. Apart from stdio,
+/// any FDs which the standard library or tokio use while spawning have `FD_CLOEXEC` set in the
+/// child, so none of them are passed on unless a mapping explicitly refers to them.
+///
+/// Commands of other runtimes which offer neither `pre_exec` nor access to an underlying
+/// [`std::process::Command`] can't be extended directly. Notably this includes async-std's
+/// `Command`, but that can be converted from a [`std::process::Command`] with `From`, which keeps
+/// its hooks. So add the mappings to a [`std::process::Command`] first, and convert it afterwards.
+///
+/// When the mappings in a call form a cycle, such as swapping two FDs, one FD of each cycle is
+/// duplicated to a temporary FD while the others are moved. Temporary FDs are only ever taken from
+/// FD numbers which are unused in the child at the time, starting one above the highest FD of any
+/// of the mappings, and have `FD_CLOEXEC` set until they are closed again once the mappings have
+/// been applied. This all happens in the child's own copy of the FD table after forking, so
+/// however high or sparse the FD numbers involved, temporaries never affect the parent's FDs, nor
+/// any other FDs which the child inherits.
+///
+/// So that the child can't run out of FDs for its temporaries, for example because other threads
+/// of the parent opened FDs up to the `RLIMIT_NOFILE` soft limit in the meantime, the FD numbers
+/// they will take are reserved in the parent when the mappings are added. The reserved FDs have
+/// `FD_CLOEXEC` set and are kept open for as long as the command is, and the child duplicates onto
+/// them rather than looking for free FDs. If they can't be reserved, for example because the
+/// parent is already out of FDs, the child looks for free FDs itself as before. Mappings which
+/// need no temporary FDs reserve nothing.
+///
+/// Note that registering any `pre_exec` hook stops the standard library from spawning the command
+/// with `posix_spawn`, so it has to fork and exec instead. There is no way to hand the mappings to
+/// the standard library as `posix_spawn` file actions, and spawning with `posix_spawn` separately
+/// couldn't produce a [`std::process::Child`] or follow the command's stdio configuration, so this
+/// library doesn't offer a `posix_spawn` path.
+pub trait CommandFdExt {
+    /// Adds the given set of file descriptors to the command.
+    ///
+    /// If this is called more than once on the same command, the sets of mappings are applied one
+    /// after another as described [above](Self).
+    fn fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingCollision>;
+
+    /// Like [`fd_mappings`](Self::fd_mappings), but checks the whole configuration up front and
+    /// reports every problem which can be detected before spawning, such as negative FDs,
+    /// conflicting mappings or parent FDs which aren't open.
+    ///
+    /// Only errors which can't be detected until the mappings are actually applied in the child,
+    /// such as `dup2` failing, are left to be reported when the command is spawned.
+    fn try_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingError>;
+
+    /// Like [`try_fd_mappings`](Self::try_fd_mappings), but doesn't check that the parent FDs are
+    /// open yet, for when they will only be opened later on, before the command is spawned or by
+    /// an earlier `pre_exec` hook.
+    ///
+    /// If a parent FD still isn't open when the mappings are applied, spawning the command fails
+    /// with `EBADF`.
+    fn deferred_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingError>;
+
+    /// Like [`try_fd_mappings`](Self::try_fd_mappings), but returns a handle which can clear the
+    /// mappings from the command again, so that children spawned from it afterwards don't get them.
+    ///
+    /// Clearing only affects these mappings, not those added by other calls, and dropping the
+    /// handle without clearing it leaves the mappings in place.
+    fn clearable_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<FdMappingsHandle, FdMappingError>;
+
+    /// Like [`try_fd_mappings`](Self::try_fd_mappings), but rather than failing if the mappings
+    /// need FDs above the `RLIMIT_NOFILE` soft limit, raises the child's soft limit far enough to
+    /// cover them, plus some headroom for the child to open FDs of its own.
+    ///
+    /// The limit is raised in the child just before the mappings are applied, so the parent's limit
+    /// is left alone, and the child keeps the raised limit after exec. If the hard limit is too low
+    /// to cover the mappings then this fails with [`FdMappingError::HardFdLimit`] instead.
+    fn raised_limit_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingError>;
+
+    /// Adds mappings from each source to the given child FD, where the source is anything which
+    /// can lend out an FD, such as a `&File` or a [`BorrowedFd`](std::os::unix::io::BorrowedFd).
+    ///
+    /// Each source is duplicated when this is called, and the command keeps the duplicate open
+    /// until it is dropped. This means the sources themselves may be closed at any point afterwards
+    /// without affecting what the child receives.
+    fn cloned_fd_mappings<S: AsFd>(
+        &mut self,
+        mappings: impl IntoIterator<Item = (S, RawFd)>,
+    ) -> Result<&mut Self, FdMappingError>;
+
+    /// Adds mappings from each owned FD to the given child FD, taking ownership of the FDs.
+    ///
+    /// The command keeps the FDs open until it is dropped, so they can't be closed by mistake before
+    /// the child is spawned, and are closed in the parent once the command is dropped after
+    /// spawning. This is useful for passing one end of a pipe or socket pair to the child: drop the
+    /// command once the child is spawned, and the parent will only be left with its own end.
+    fn owned_fd_mappings(
+        &mut self,
+        mappings: Vec<(OwnedFd, RawFd)>,
+    ) -> Result<&mut Self, FdMappingError>;
+
+    /// Like [`owned_fd_mappings`](Self::owned_fd_mappings), but returns a guard which owns the
+    /// parent's copies of the FDs instead of leaving them to the command, so they can be closed
+    /// straight after spawning without dropping the command.
+    ///
+    /// The guard closes the FDs when it is dropped or [closed](PassedFds::close). Do that once the
+    /// child is spawned, so that for example reading the other end of a pipe reaches EOF as soon as
+    /// the child closes its copy. Spawning the command after that fails with `EBADF`, rather than
+    /// mapping whatever else may have been opened at the same FD numbers since.
+    fn guarded_fd_mappings(
+        &mut self,
+        mappings: Vec<(OwnedFd, RawFd)>,
+    ) -> Result<PassedFds, FdMappingError>;
+
+    /// Adds mappings from each borrowed FD to the given child FD, and returns the command borrowed
+    /// for as long as the FDs are, so the compiler checks that they stay open until it is spawned.
+    ///
+    /// This is lighter than handing over an [`OwnedFd`], for callers who keep the `File` or socket
+    /// around anyway, and nothing is duplicated. Spawn the command through the returned
+    /// [`BorrowedFdCommand`], which derefs to it, for example with `.spawn()` or `.output()`. Once
+    /// that is dropped the command may be used directly again, but as the FDs may have been closed
+    /// by then, spawning it fails with `EBADF` rather than mapping whatever else is open at the
+    /// same FD numbers.
+    ///
+    /// Dropping the sources while the command is still borrowed doesn't compile:
+    ///
+    /// ```compile_fail
+    /// use std::fs::File;
+    /// use std::os::unix::io::AsFd;
+    /// use std::process::Command;
+    /// use tokio_command_fds::CommandFdExt;
+    ///
+    /// let mut command = Command::new("ls");
+    /// let mut borrowed = {
+    ///     let file = File::open("Cargo.toml").unwrap();
+    ///     command.borrowed_fd_mappings([(file.as_fd(), 3)]).unwrap()
+    /// };
+    /// borrowed.spawn().unwrap();
+    /// ```
+    fn borrowed_fd_mappings<'a>(
+        &'a mut self,
+        mappings: impl IntoIterator<Item = (BorrowedFd<'a>, RawFd)>,
+    ) -> Result<BorrowedFdCommand<'a, Self>, FdMappingError>
+    where
+        Self: Sized;
+
+    /// Adds the given set of file descriptors to be passed on to the child process when the command
+    /// is run, keeping the same FD numbers.
+    ///
+    /// This just clears `FD_CLOEXEC` on each FD in the child, without remapping anything. If an FD
+    /// is also the parent FD of a mapping it is kept open at both numbers. If it is the child FD of
+    /// a mapping then the mapping wins, regardless of which was added first: either the mapping
+    /// replaces the preserved FD, or clearing `FD_CLOEXEC` on its result changes nothing. Temporary
+    /// FDs used while applying mappings are only ever allocated from unused FD numbers, so never
+    /// clobber a preserved FD.
+    fn preserved_fds(&mut self, fds: Vec<RawFd>) -> &mut Self;
+
+    /// Like [`preserved_fds`](Self::preserved_fds), but for every FD in the given range.
+    ///
+    /// If `skip_closed` is set then any FDs in the range which aren't open in the child are
+    /// skipped, otherwise spawning the command fails with `EBADF` if there are any.
+    fn preserved_fd_range(&mut self, fds: RangeInclusive<RawFd>, skip_closed: bool) -> &mut Self;
+
+    /// Adds the given mappings, and also passes each FD in `inherit` on to the child at its own
+    /// number, like a plain fork and exec would for the FDs listed.
+    ///
+    /// This is for children which expect to inherit some of the parent's FDs as well as a few
+    /// remapped ones. `FD_CLOEXEC` is cleared on each inherited FD in the same hook as the mappings,
+    /// and nothing else about them is changed: their blocking mode is inherited, and stdio may be
+    /// listed. Unlike [`preserved_fds`](Self::preserved_fds), which adds a hook of its own, the
+    /// inherited FDs are planned together with the mappings and checked like
+    /// [`try_fd_mappings`](Self::try_fd_mappings), so one which isn't open is reported up front.
+    ///
+    /// If an FD is both inherited and the child FD of a mapping, the mapping wins, and the child
+    /// gets the mapping's parent FD at that number. If an FD is both inherited and the parent FD of
+    /// a mapping, the child gets it at both numbers, even if the mapping has
+    /// [`move_source`](FdMapping::move_source) set.
+    fn inherited_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+        inherit: impl IntoIterator<Item = RawFd>,
+    ) -> Result<&mut Self, FdMappingError> {
+        let mut mappings = mappings.into();
+        let mut inherit: Vec<RawFd> = inherit.into_iter().collect();
+        inherit.sort_unstable();
+        inherit.dedup();
+        let inherited: Vec<FdMapping> = inherit
+            .into_iter()
+            .filter(|&fd| !mappings.iter().any(|mapping| mapping.child_fd == fd))
+            .map(|fd| {
+                FdMapping::new(fd, fd)
+                    .with_blocking_mode(BlockingMode::Inherit)
+                    .with_stdio_policy(StdioPolicy::Allow)
+            })
+            .collect();
+        mappings.extend(inherited);
+        self.try_fd_mappings(mappings)
+    }
+
+    /// Ensures that the child process doesn't inherit any FDs other than stdin, stdout, stderr and
+    /// those passed to it by mappings or [`preserved_fds`](Self::preserved_fds).
+    ///
+    /// This sets `FD_CLOEXEC` on every FD from 3 upwards in the child, using `close_range(2)` where
+    /// available and otherwise checking each FD up to the `RLIMIT_NOFILE` soft limit. The mappings
+    /// and preserved FDs then clear it again on exactly the FDs they pass on, so this must be called
+    /// before adding any of them. If it is called afterwards then spawning the command will fail
+    /// with `EINVAL`, rather than silently dropping the FDs which were meant to be passed on.
+    fn close_other_fds(&mut self) -> &mut Self;
+
+    /// Maps the given FDs to consecutive child FDs starting at `first_child_fd` (usually 3, just
+    /// after stdio), in the order given.
+    ///
+    /// Returns the mappings which were added, so the parent can tell the child which FD is which,
+    /// for example through its arguments or environment.
+    fn compact_fd_mappings(
+        &mut self,
+        fds: Vec<RawFd>,
+        first_child_fd: RawFd,
+    ) -> Result<Vec<FdMapping>, FdMappingError>;
+
+    /// Adds mappings from each parent FD to the given child FD, or where no child FD is given, to
+    /// the lowest child FD at or above `first_child_fd` which no other mapping in the call uses.
+    ///
+    /// FDs are assigned in the order given, so the result only depends on the arguments. Returns
+    /// all the mappings which were added, in the same order, so the parent can tell the child which
+    /// FD is which.
+    fn assigned_fd_mappings(
+        &mut self,
+        mappings: Vec<(RawFd, Option<RawFd>)>,
+        first_child_fd: RawFd,
+    ) -> Result<Vec<FdMapping>, FdMappingError> {
+        let mappings = assign_child_fds(&mappings, first_child_fd);
+        self.try_fd_mappings(mappings.clone())?;
+        Ok(mappings)
+    }
+
+    /// Like [`assigned_fd_mappings`](Self::assigned_fd_mappings), but also sets the named
+    /// environment variable for each mapping to its child FD in decimal, so the child is told
+    /// exactly where to find each FD.
+    ///
+    /// The variables are set to the child FDs assigned here in the parent, so they can't drift
+    /// apart from the mappings. They are only set if the mappings are added successfully.
+    fn advertised_fd_mappings<K: AsRef<OsStr>>(
+        &mut self,
+        mappings: Vec<(K, RawFd, Option<RawFd>)>,
+        first_child_fd: RawFd,
+    ) -> Result<Vec<FdMapping>, FdMappingError>;
+
+    /// Like [`compact_fd_mappings`](Self::compact_fd_mappings) from FD 3, but takes whole
+    /// mappings, ignoring their child FDs but keeping their other options.
+    ///
+    /// The mappings are packed into consecutive child FDs from 3 in the order given, so they never
+    /// replace stdio whatever child FDs they were given. Returns the mappings which were added, in
+    /// the same order, so the `i`th one is at child FD `3 + i`. Together with
+    /// [`close_other_fds`](Self::close_other_fds), called first, this gives the child a sanitized
+    /// FD table with nothing but stdio and these FDs.
+    fn sanitized_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<Vec<FdMapping>, FdMappingError> {
+        let mappings: Vec<FdMapping> = mappings
+            .into()
+            .into_iter()
+            .zip(3..)
+            .map(|(mapping, child_fd)| FdMapping {
+                child_fd,
+                ..mapping
+            })
+            .collect();
+        self.try_fd_mappings(mappings.clone())?;
+        Ok(mappings)
+    }
+
+    /// Adds the given mappings with every child FD inside `window`, for environments such as
+    /// containers where only low FD numbers are usable.
+    ///
+    /// Mappings whose child FD is already inside the window keep it. The others are moved to the
+    /// lowest FDs in the window which no mapping has taken, in the order given, keeping their
+    /// other options. To tie the window to the `RLIMIT_NOFILE` soft limit, end it at
+    /// [`max_usable_fd`]` + 1`. Fails with [`FdMappingError::WindowTooSmall`] if the mappings don't
+    /// all fit, and otherwise returns the mappings which were added, in the same order. Temporary
+    /// FDs for cycles may still be needed above the window, but are checked against the limit like
+    /// [`try_fd_mappings`](Self::try_fd_mappings) checks everything else.
+    fn clamped_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+        window: Range<RawFd>,
+    ) -> Result<Vec<FdMapping>, FdMappingError> {
+        let mappings: Vec<FdMapping> = mappings.into().into();
+        let taken: Vec<RawFd> = mappings
+            .iter()
+            .map(|mapping| mapping.child_fd)
+            .filter(|fd| window.contains(fd))
+            .collect();
+        let mut free = window.clone().filter(|fd| !taken.contains(fd));
+        let count = mappings.len();
+        let clamped = mappings
+            .into_iter()
+            .map(|mapping| {
+                if window.contains(&mapping.child_fd) {
+                    return Ok(mapping);
+                }
+                let child_fd = free.next().ok_or(FdMappingError::WindowTooSmall {
+                    count,
+                    start: window.start,
+                    end: window.end,
+                })?;
+                Ok(FdMapping {
+                    child_fd,
+                    ..mapping
+                })
+            })
+            .collect::<Result<Vec<_>, FdMappingError>>()?;
+        self.try_fd_mappings(clamped.clone())?;
+        Ok(clamped)
+    }
+
+    /// Passes the given FDs to the child using the systemd socket activation protocol.
+    ///
+    /// The FDs are mapped to consecutive child FDs starting at [`SD_LISTEN_FDS_START`], in the order
+    /// given, and `LISTEN_FDS` is set to the number of FDs. Note that strict implementations of the
+    /// protocol such as `sd_listen_fds` also require `LISTEN_PID` to be set to the child's PID,
+    /// which isn't known until after the child has been forked, so this doesn't set it. To set it
+    /// too, create the command with [`listen_pid_command`].
+    ///
+    /// Mappings added by other calls are applied separately, so one which lands in the range of
+    /// activation FDs would silently replace an activation FD. Use
+    /// [`socket_activation_fds_with`](Self::socket_activation_fds_with) to pass other FDs at the
+    /// same time and have that checked.
+    fn socket_activation_fds(&mut self, fds: Vec<RawFd>) -> Result<&mut Self, FdMappingError>;
+
+    /// Like [`socket_activation_fds`](Self::socket_activation_fds), but also adds the given
+    /// unrelated mappings in the same set, failing with [`FdMappingError::DuplicateTarget`] if any
+    /// of them would land on one of the FDs from [`SD_LISTEN_FDS_START`] to
+    /// `SD_LISTEN_FDS_START + fds.len() - 1` which the activation protocol uses.
+    fn socket_activation_fds_with(
+        &mut self,
+        fds: Vec<RawFd>,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingError>;
+
+    /// Removes the environment variables used by systemd socket activation and service
+    /// notification (such as `LISTEN_FDS` and `NOTIFY_SOCKET`) from the child's environment, so
+    /// it doesn't act on FDs or sockets which were meant for this process rather than for it.
+    ///
+    /// Any of them which have been set explicitly on the command, such as `LISTEN_FDS` by
+    /// [`socket_activation_fds`](Self::socket_activation_fds), are left alone.
+    fn clear_activation_env(&mut self) -> &mut Self;
+
+    /// Passes the given bytes to the child as a sealed, read-only in-memory file at `child_fd`,
+    /// without anything touching the filesystem.
+    ///
+    /// The file is created with `memfd_create(2)` and sealed against any further changes. Its
+    /// offset is reset to the start in the child just before exec, so each child spawned from the
+    /// command can read the whole thing.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn memfd_to_child(&mut self, data: &[u8], child_fd: RawFd)
+        -> Result<&mut Self, FdMappingError>;
+
+    /// Creates an `eventfd(2)` with the given initial value and flags for the parent and child to
+    /// signal each other, mapping it to `child_fd` in the child and returning the parent's copy.
+    ///
+    /// `EFD_CLOEXEC` is always added to the flags, so the returned FD won't leak into this or any
+    /// other child. The child's copy is owned by the command and closed when it is dropped. Both
+    /// copies share the counter and the `O_NONBLOCK` flag, so `EFD_NONBLOCK` applies to the child
+    /// too, which is why its mapping leaves the flag alone rather than clearing it.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn eventfd_to_child(
+        &mut self,
+        initial_value: u32,
+        flags: EfdFlags,
+        child_fd: RawFd,
+    ) -> Result<OwnedFd, FdMappingError>;
+
+    /// Adds the given mappings like [`try_fd_mappings`](Self::try_fd_mappings), then spawns the
+    /// command synchronously, returning a [`std::process::Child`].
+    ///
+    /// This doesn't need an async runtime, so it can be used to pass FDs from synchronous code such
+    /// as build scripts. A tokio command is spawned through its underlying
+    /// [`std::process::Command`], so tokio-specific settings such as `kill_on_drop` don't apply to
+    /// the child.
+    ///
+    /// The mappings are added to the command each time this is called, so to retry after spawning
+    /// fails, spawn the command itself again instead.
+    fn spawn_blocking_with_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<std::process::Child, FdMappingError>;
+
+    /// Like [`try_fd_mappings`](Self::try_fd_mappings), but returns a [`MappingDiagnostics`] which
+    /// can say which mapping failed if applying them fails when the command is spawned.
+    ///
+    /// Otherwise spawning only reports the OS error, as that is all a `pre_exec` hook can pass back
+    /// to the parent. [`spawn_blocking_with_fd_mappings`](Self::spawn_blocking_with_fd_mappings)
+    /// uses this to report the failing mapping itself.
+    fn diagnosed_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<MappingDiagnostics, FdMappingError>;
+
+    /// Opens the file at `path` with the given options and passes it to the child at `child_fd`,
+    /// such as a read-only data file or a log file opened for appending.
+    ///
+    /// The command owns the parent's copy of the file until it is dropped, like with
+    /// [`owned_fd_mappings`](Self::owned_fd_mappings).
+    fn file_to_child(
+        &mut self,
+        path: impl AsRef<Path>,
+        options: &OpenOptions,
+        child_fd: RawFd,
+    ) -> Result<&mut Self, FdMappingError> {
+        let file = options.open(path)?;
+        self.owned_fd_mappings(vec![(file.into(), child_fd)])
+    }
+
+    /// Opens `/dev/null` and passes it to the child at `child_fd`, to give the child an FD which
+    /// provides no input or discards whatever is written to it.
+    ///
+    /// It is opened write-only if `writable` is set, and otherwise read-only. Like with
+    /// [`file_to_child`](Self::file_to_child), the command owns the parent's copy until it is
+    /// dropped. `child_fd` may be one of stdin, stdout or stderr, to null out that stream whatever
+    /// the command's stdio configuration is.
+    fn dev_null_to_child(
+        &mut self,
+        child_fd: RawFd,
+        writable: bool,
+    ) -> Result<&mut Self, FdMappingError> {
+        let mut options = OpenOptions::new();
+        if writable {
+            options.write(true);
+        } else {
+            options.read(true);
+        }
+        self.file_to_child("/dev/null", &options, child_fd)
+    }
+
+    /// Opens the directory at `path` and passes it to the child at `child_fd`, for a sandboxed child
+    /// which confines itself to the directory by opening files relative to it with `openat(2)` and
+    /// the other `*at` syscalls.
+    ///
+    /// On Linux and Android the directory is opened with `O_PATH`. The FD is then purely a
+    /// reference to the directory, which can be used as the base of `openat` or with `fchdir`, but
+    /// can't be used to list the directory. Use [`file_to_child`](Self::file_to_child) with
+    /// `O_DIRECTORY` for that. Elsewhere it is opened read-only. Either way `FD_CLOEXEC` is only
+    /// cleared on the child's copy, and the command owns the parent's copy until it is dropped,
+    /// like with [`owned_fd_mappings`](Self::owned_fd_mappings).
+    fn directory_to_child(
+        &mut self,
+        path: impl AsRef<Path>,
+        child_fd: RawFd,
+    ) -> Result<&mut Self, FdMappingError>;
+
+    /// Passes this process's own stdin to the child at `child_fd`, such as for a wrapper which
+    /// forwards its terminal to the program it runs.
+    ///
+    /// Stdin is duplicated when this is called, like with
+    /// [`cloned_fd_mappings`](Self::cloned_fd_mappings), so the child gets this process's stdin
+    /// whatever the command's [`stdin`](std::process::Command::stdin) configuration is. A mapping
+    /// from FD 0 would instead refer to the child's stdin as configured, such as the pipe for
+    /// [`Stdio::piped`](std::process::Stdio::piped).
+    fn parent_stdin_to_child(&mut self, child_fd: RawFd) -> Result<&mut Self, FdMappingError> {
+        self.cloned_fd_mappings([(io::stdin(), child_fd)])
+    }
+
+    /// Like [`parent_stdin_to_child`](Self::parent_stdin_to_child), but for this process's stdout.
+    fn parent_stdout_to_child(&mut self, child_fd: RawFd) -> Result<&mut Self, FdMappingError> {
+        self.cloned_fd_mappings([(io::stdout(), child_fd)])
+    }
+
+    /// Like [`parent_stdin_to_child`](Self::parent_stdin_to_child), but for this process's stderr.
+    fn parent_stderr_to_child(&mut self, child_fd: RawFd) -> Result<&mut Self, FdMappingError> {
+        self.cloned_fd_mappings([(io::stderr(), child_fd)])
+    }
+
+    /// Creates a pipe for the parent to send data to the child, mapping its read end to `child_fd`
+    /// in the child and returning its write end.
+    ///
+    /// The returned write end has `FD_CLOEXEC` set, so it won't leak into this or any other child.
+    /// The parent's copy of the read end is owned by the command and closed when it is dropped.
+    fn pipe_to_child(&mut self, child_fd: RawFd) -> Result<OwnedFd, FdMappingError> {
+        let (read_end, write_end) = cloexec_pipe()?;
+        self.owned_fd_mappings(vec![(read_end, child_fd)])?;
+        Ok(write_end)
+    }
+
+    /// Creates a pipe for the child to send data to the parent, mapping its write end to
+    /// `child_fd` in the child and returning its read end.
+    ///
+    /// The returned read end has `FD_CLOEXEC` set, so it won't leak into this or any other child.
+    /// The parent's copy of the write end is owned by the command and closed when it is dropped,
+    /// after which reading will reach EOF once the child closes its copy or exits.
+    fn pipe_from_child(&mut self, child_fd: RawFd) -> Result<OwnedFd, FdMappingError> {
+        let (read_end, write_end) = cloexec_pipe()?;
+        self.owned_fd_mappings(vec![(write_end, child_fd)])?;
+        Ok(read_end)
+    }
+
+    /// Creates a pipe for the child to send log or telemetry output to the parent on a channel of
+    /// its own, mapping its write end to `child_fd` in the child and returning its read end as a
+    /// tokio pipe [`Receiver`](tokio::net::unix::pipe::Receiver), so the parent can read it
+    /// asynchronously without it being interleaved with stdout.
+    ///
+    /// This must be called from within a tokio runtime, to register the returned read end with it.
+    /// The read end has `FD_CLOEXEC` set, so it won't leak into this or any other child. The
+    /// parent's copy of the write end is owned by the returned [`PassedFds`], like with
+    /// [`guarded_fd_mappings`](Self::guarded_fd_mappings): close it once the child is spawned, and
+    /// reading will reach EOF once the child closes its copy or exits.
+    #[cfg(feature = "tokio")]
+    fn log_pipe_from_child(
+        &mut self,
+        child_fd: RawFd,
+    ) -> Result<(tokio::net::unix::pipe::Receiver, PassedFds), FdMappingError> {
+        let (read_end, write_end) = cloexec_pipe()?;
+        let passed = self.guarded_fd_mappings(vec![(write_end, child_fd)])?;
+        let receiver = tokio::net::unix::pipe::Receiver::from_owned_fd(read_end)?;
+        Ok((receiver, passed))
+    }
+
+    /// Creates a connected pair of Unix sockets for the parent and child to talk to each other,
+    /// mapping one to `child_fd` in the child and returning the other as a tokio
+    /// [`UnixStream`](tokio::net::UnixStream).
+    ///
+    /// This must be called from within a tokio runtime, to register the returned socket with it.
+    /// Like with pipes, the returned end has `FD_CLOEXEC` set, and the parent's copy of the child's
+    /// end is owned by the command and closed when it is dropped.
+    #[cfg(feature = "tokio")]
+    fn socketpair_to_child(
+        &mut self,
+        child_fd: RawFd,
+    ) -> Result<tokio::net::UnixStream, FdMappingError> {
+        let (parent_end, child_end) = std::os::unix::net::UnixStream::pair()?;
+        parent_end.set_nonblocking(true)?;
+        let parent_end = tokio::net::UnixStream::from_std(parent_end)?;
+        self.owned_fd_mappings(vec![(child_end.into(), child_fd)])?;
+        Ok(parent_end)
+    }
+
+    /// Passes a listening socket such as a [`std::net::TcpListener`] or a
+    /// [`std::os::unix::net::UnixListener`] to the child at `child_fd`, taking ownership of it.
+    ///
+    /// The socket is put into blocking mode unless `nonblocking` is set, as that is what most
+    /// programs expect to inherit. The mode is shared by every copy of the socket, but the parent
+    /// doesn't keep one: the command owns the socket until it is dropped, after which only the
+    /// child has it. A tokio listener can be passed by taking it out of its runtime with
+    /// [`take_raw_fd_for_child`] first.
+    fn listener_to_child(
+        &mut self,
+        listener: impl Into<OwnedFd>,
+        child_fd: RawFd,
+        nonblocking: bool,
+    ) -> Result<&mut Self, FdMappingError>;
+
+    /// Passes a bound UDP socket to the child at `child_fd`, taking ownership of it, for a child
+    /// which receives datagrams on it.
+    ///
+    /// This is the same as [`listener_to_child`](Self::listener_to_child) with `nonblocking`
+    /// unset, so the socket is put into blocking mode and owned by the command until it is
+    /// dropped. A tokio `UdpSocket` can be passed by taking it out of its runtime with
+    /// [`take_raw_fd_for_child`] and converting the result with `From`.
+    fn udp_socket_to_child(
+        &mut self,
+        socket: std::net::UdpSocket,
+        child_fd: RawFd,
+    ) -> Result<&mut Self, FdMappingError> {
+        self.listener_to_child(socket, child_fd, false)
+    }
+
+    /// Passes a [`socket2::Socket`] to the child at `child_fd`, taking ownership of it, for sockets
+    /// which need options such as `SO_REUSEADDR` set before they are bound.
+    ///
+    /// This is the same as [`listener_to_child`](Self::listener_to_child), so the socket is put
+    /// into blocking mode unless `nonblocking` is set, and owned by the command until it is
+    /// dropped.
+    #[cfg(feature = "socket2")]
+    fn socket2_to_child(
+        &mut self,
+        socket: socket2::Socket,
+        child_fd: RawFd,
+        nonblocking: bool,
+    ) -> Result<&mut Self, FdMappingError> {
+        self.listener_to_child(socket, child_fd, nonblocking)
+    }
+
+    /// Passes an FD received from another process, such as over a Unix socket with `SCM_RIGHTS`,
+    /// on to the child at `child_fd`, taking ownership of it.
+    ///
+    /// The flags are normalised with [`prepare_received_fd`] first, and the FD is then owned by
+    /// the command like with [`listener_to_child`](Self::listener_to_child).
+    fn received_fd_to_child(
+        &mut self,
+        fd: OwnedFd,
+        child_fd: RawFd,
+        nonblocking: bool,
+    ) -> Result<&mut Self, FdMappingError> {
+        let fd = prepare_received_fd(fd, nonblocking)?;
+        self.listener_to_child(fd, child_fd, nonblocking)
+    }
+}
+
+impl CommandFdExt for Command {
+    fn fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingCollision> {
+        let plan = MappingPlan::new(&mappings.into())?;
+        Ok(register_plan(self, plan, Vec::new()))
+    }
+
+    fn try_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingError> {
+        let mappings = mappings.into();
+        check_stdio_targets(&mappings)?;
+        let plan = checked_plan(&mappings, true)?;
+        Ok(register_plan(self, plan, Vec::new()))
+    }
+
+    fn clearable_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<FdMappingsHandle, FdMappingError> {
+        let mappings = mappings.into();
+        check_stdio_targets(&mappings)?;
+        let mut prepared = PreparedFdMappings::from_plan(checked_plan(&mappings, true)?);
+        let handle = FdMappingsHandle {
+            cleared: Arc::new(AtomicBool::new(false)),
+        };
+        let cleared = handle.cleared.clone();
+
+        // Safety: `map_fds` will not allocate, so it is safe to call from this hook, which only ever
+        // runs in the forked child.
+        unsafe {
+            self.pre_exec(move || {
+                if cleared.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                prepared.map_fds()
+            });
+        }
+
+        Ok(handle)
+    }
+
+    fn raised_limit_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingError> {
+        let mappings = mappings.into();
+        validate_fds(&mappings)?;
+        check_stdio_targets(&mappings)?;
+        let mut plan = MappingPlan::new(&mappings)?;
+        plan.raise_fd_limit()?;
+        check_parent_fds_open(&mappings)?;
+        Ok(register_plan(self, plan, Vec::new()))
+    }
+
+    fn deferred_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingError> {
+        let mappings = mappings.into();
+        check_stdio_targets(&mappings)?;
+        let plan = checked_plan(&mappings, false)?;
+        Ok(register_plan(self, plan, Vec::new()))
+    }
+
+    fn cloned_fd_mappings<S: AsFd>(
+        &mut self,
+        mappings: impl IntoIterator<Item = (S, RawFd)>,
+    ) -> Result<&mut Self, FdMappingError> {
+        let mappings = mappings
+            .into_iter()
+            .map(|(source, child_fd)| Ok((source.as_fd().try_clone_to_owned()?, child_fd)))
+            .collect::<io::Result<_>>()?;
+        self.owned_fd_mappings(mappings)
+    }
+
+    fn owned_fd_mappings(
+        &mut self,
+        mappings: Vec<(OwnedFd, RawFd)>,
+    ) -> Result<&mut Self, FdMappingError> {
+        let (owned_fds, fd_mappings) = split_owned_mappings(mappings);
+        // The FDs are owned, so they must be open.
+        let plan = checked_plan(&fd_mappings, false)?;
+        Ok(register_plan(self, plan, owned_fds))
+    }
+
+    fn guarded_fd_mappings(
+        &mut self,
+        mappings: Vec<(OwnedFd, RawFd)>,
+    ) -> Result<PassedFds, FdMappingError> {
+        let (owned_fds, fd_mappings) = split_owned_mappings(mappings);
+        let plan = checked_plan(&fd_mappings, false)?;
+        let mut prepared = PreparedFdMappings::from_plan(plan);
+        let guard = PassedFds::new(owned_fds);
+        let state = guard.state.clone();
+
+        // Safety: `map_fds` will not allocate, so it is safe to call from this hook, which only ever
+        // runs in the forked child.
+        unsafe {
+            self.pre_exec(move || {
+                // The guard sets this before closing the FDs, so if it is clear here then they were
+                // still open when the child was forked.
+                if state.closed.load(Ordering::SeqCst) {
+                    return Err(Errno::EBADF.into());
+                }
+                prepared.map_fds()
+            });
+        }
+
+        Ok(guard)
+    }
+
+    fn borrowed_fd_mappings<'a>(
+        &'a mut self,
+        mappings: impl IntoIterator<Item = (BorrowedFd<'a>, RawFd)>,
+    ) -> Result<BorrowedFdCommand<'a, Self>, FdMappingError> {
+        let released = register_borrowed(self, mappings)?;
+        Ok(BorrowedFdCommand {
+            command: self,
+            released,
+        })
+    }
+
+    fn preserved_fds(&mut self, fds: Vec<RawFd>) -> &mut Self {
+        unsafe {
+            self.pre_exec(move || preserve_fds(&fds));
+        }
+
+        self
+    }
+
+    fn preserved_fd_range(&mut self, fds: RangeInclusive<RawFd>, skip_closed: bool) -> &mut Self {
+        // Safety: `preserve_fd_range` only makes syscalls, it doesn't allocate.
+        unsafe {
+            self.pre_exec(move || preserve_fd_range(fds.clone(), skip_closed));
+        }
+
+        self
+    }
+
+    fn close_other_fds(&mut self) -> &mut Self {
+        // Safety: `set_cloexec_from` only makes syscalls, it doesn't allocate.
+        unsafe {
+            self.pre_exec(|| {
+                if FDS_PASSED.load(Ordering::SeqCst) {
+                    // This would undo mappings which have already been applied.
+                    return Err(Errno::EINVAL.into());
+                }
+                set_cloexec_from(3)
+            });
+        }
+
+        self
+    }
+
+    fn compact_fd_mappings(
+        &mut self,
+        fds: Vec<RawFd>,
+        first_child_fd: RawFd,
+    ) -> Result<Vec<FdMapping>, FdMappingError> {
+        let mappings: Vec<FdMapping> = fds
+            .into_iter()
+            .zip(first_child_fd..)
+            .map(|(parent_fd, child_fd)| FdMapping::new(parent_fd, child_fd))
+            .collect();
+        self.try_fd_mappings(mappings.clone())?;
+        Ok(mappings)
+    }
+
+    fn advertised_fd_mappings<K: AsRef<OsStr>>(
+        &mut self,
+        mappings: Vec<(K, RawFd, Option<RawFd>)>,
+        first_child_fd: RawFd,
+    ) -> Result<Vec<FdMapping>, FdMappingError> {
+        let fds: Vec<(RawFd, Option<RawFd>)> = mappings
+            .iter()
+            .map(|&(_, parent_fd, child_fd)| (parent_fd, child_fd))
+            .collect();
+        let added = self.assigned_fd_mappings(fds, first_child_fd)?;
+        for ((name, _, _), mapping) in mappings.iter().zip(&added) {
+            self.env(name, mapping.child_fd.to_string());
+        }
+        Ok(added)
+    }
+
+    fn socket_activation_fds(&mut self, fds: Vec<RawFd>) -> Result<&mut Self, FdMappingError> {
+        self.socket_activation_fds_with(fds, FdMappings::new())
+    }
+
+    fn socket_activation_fds_with(
+        &mut self,
+        fds: Vec<RawFd>,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingError> {
+        let count = fds.len();
+        let mut all_mappings: FdMappings = fds
+            .into_iter()
+            .zip(SD_LISTEN_FDS_START..)
+            .map(|(parent_fd, child_fd)| FdMapping::new(parent_fd, child_fd))
+            .collect();
+        // Any of the other mappings which land among the activation FDs are caught as duplicates.
+        all_mappings.extend(mappings.into());
+        self.try_fd_mappings(all_mappings)?;
+        Ok(self.env("LISTEN_FDS", count.to_string()))
+    }
+
+    fn clear_activation_env(&mut self) -> &mut Self {
+        for name in ACTIVATION_ENV_VARS {
+            let explicitly_set = self
+                .get_envs()
+                .any(|(key, value)| key == *name && value.is_some());
+            if !explicitly_set {
+                self.env_remove(name);
+            }
+        }
+        self
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn memfd_to_child(
+        &mut self,
+        data: &[u8],
+        child_fd: RawFd,
+    ) -> Result<&mut Self, FdMappingError> {
+        let name = CStr::from_bytes_with_nul(b"tokio-command-fds\0").unwrap();
+        let fd = memfd_create(
+            name,
+            MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+        )
+        .map_err(io::Error::from)?;
+        // Safety: memfd_create returns a newly opened FD, which nothing else owns.
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(data)?;
+        fcntl(
+            file.as_raw_fd(),
+            FcntlArg::F_ADD_SEALS(
+                SealFlag::F_SEAL_SEAL
+                    | SealFlag::F_SEAL_SHRINK
+                    | SealFlag::F_SEAL_GROW
+                    | SealFlag::F_SEAL_WRITE,
+            ),
+        )
+        .map_err(io::Error::from)?;
+
+        // The offset is shared with every child the file is passed to, so rewind it each time.
+        let mapping = FdMapping::new(file.as_raw_fd(), child_fd).with_seek(SeekPosition::Start(0));
+        let plan = checked_plan(std::slice::from_ref(&mapping), false)?;
+        Ok(register_plan(self, plan, vec![file.into()]))
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn eventfd_to_child(
+        &mut self,
+        initial_value: u32,
+        flags: EfdFlags,
+        child_fd: RawFd,
+    ) -> Result<OwnedFd, FdMappingError> {
+        let fd = eventfd(initial_value, flags | EfdFlags::EFD_CLOEXEC).map_err(io::Error::from)?;
+        // Safety: eventfd returns a newly opened FD, which nothing else owns.
+        let parent_end = unsafe { OwnedFd::from_raw_fd(fd) };
+        let child_end = parent_end.try_clone()?;
+        let mapping = FdMapping::new(child_end.as_raw_fd(), child_fd)
+            .with_blocking_mode(BlockingMode::Inherit);
+        let plan = checked_plan(std::slice::from_ref(&mapping), false)?;
+        register_plan(self, plan, vec![child_end]);
+        Ok(parent_end)
+    }
+
+    fn directory_to_child(
+        &mut self,
+        path: impl AsRef<Path>,
+        child_fd: RawFd,
+    ) -> Result<&mut Self, FdMappingError> {
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        let flags = libc::O_DIRECTORY | libc::O_PATH;
+        #[cfg(not(any(target_os = "android", target_os = "linux")))]
+        let flags = libc::O_DIRECTORY;
+        let directory = OpenOptions::new()
+            .read(true)
+            .custom_flags(flags)
+            .open(path)?;
+        // O_NONBLOCK can't be changed through an O_PATH FD, and means nothing for a directory.
+        let mapping = FdMapping::new(directory.as_raw_fd(), child_fd)
+            .with_blocking_mode(BlockingMode::Inherit);
+        let plan = checked_plan(std::slice::from_ref(&mapping), false)?;
+        Ok(register_plan(self, plan, vec![directory.into()]))
+    }
+
+    fn listener_to_child(
+        &mut self,
+        listener: impl Into<OwnedFd>,
+        child_fd: RawFd,
+        nonblocking: bool,
+    ) -> Result<&mut Self, FdMappingError> {
+        let listener = listener.into();
+        set_nonblocking(listener.as_raw_fd(), nonblocking)?;
+        let blocking_mode = if nonblocking {
+            BlockingMode::Nonblocking
+        } else {
+            BlockingMode::Blocking
+        };
+        let mapping =
+            FdMapping::new(listener.as_raw_fd(), child_fd).with_blocking_mode(blocking_mode);
+        let plan = checked_plan(std::slice::from_ref(&mapping), false)?;
+        Ok(register_plan(self, plan, vec![listener]))
+    }
+
+    fn spawn_blocking_with_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<std::process::Child, FdMappingError> {
+        let diagnostics = self.diagnosed_fd_mappings(mappings)?;
+        self.spawn()
+            .map_err(|e| diagnostics.take_failure().unwrap_or_else(|| e.into()))
+    }
+
+    fn diagnosed_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<MappingDiagnostics, FdMappingError> {
+        let mappings = mappings.into();
+        check_stdio_targets(&mappings)?;
+        let plan = checked_plan(&mappings, true)?;
+        let diagnostics = MappingDiagnostics {
+            slot: Arc::new(FailureSlot::new()?),
+            ops: plan.ops.clone(),
+            mappings: plan.mappings.clone(),
+        };
+        let mut prepared = PreparedFdMappings::from_plan(plan);
+        prepared.failure = Some(diagnostics.slot.clone());
+        register_prepared(self, prepared, Vec::new());
+        Ok(diagnostics)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl CommandFdExt for tokio::process::Command {
+    fn fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingCollision> {
+        self.as_std_mut().fd_mappings(mappings)?;
+        Ok(self)
+    }
+
+    fn try_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingError> {
+        self.as_std_mut().try_fd_mappings(mappings)?;
+        Ok(self)
+    }
+
+    fn clearable_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<FdMappingsHandle, FdMappingError> {
+        self.as_std_mut().clearable_fd_mappings(mappings)
+    }
+
+    fn raised_limit_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingError> {
+        self.as_std_mut().raised_limit_fd_mappings(mappings)?;
+        Ok(self)
+    }
+
+    fn deferred_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingError> {
+        self.as_std_mut().deferred_fd_mappings(mappings)?;
+        Ok(self)
+    }
+
+    fn cloned_fd_mappings<S: AsFd>(
+        &mut self,
+        mappings: impl IntoIterator<Item = (S, RawFd)>,
+    ) -> Result<&mut Self, FdMappingError> {
+        self.as_std_mut().cloned_fd_mappings(mappings)?;
+        Ok(self)
+    }
+
+    fn owned_fd_mappings(
+        &mut self,
+        mappings: Vec<(OwnedFd, RawFd)>,
+    ) -> Result<&mut Self, FdMappingError> {
+        self.as_std_mut().owned_fd_mappings(mappings)?;
+        Ok(self)
+    }
+
+    fn guarded_fd_mappings(
+        &mut self,
+        mappings: Vec<(OwnedFd, RawFd)>,
+    ) -> Result<PassedFds, FdMappingError> {
+        self.as_std_mut().guarded_fd_mappings(mappings)
+    }
+
+    fn borrowed_fd_mappings<'a>(
+        &'a mut self,
+        mappings: impl IntoIterator<Item = (BorrowedFd<'a>, RawFd)>,
+    ) -> Result<BorrowedFdCommand<'a, Self>, FdMappingError> {
+        let released = register_borrowed(self.as_std_mut(), mappings)?;
+        Ok(BorrowedFdCommand {
+            command: self,
+            released,
+        })
+    }
+
+    fn preserved_fds(&mut self, fds: Vec<RawFd>) -> &mut Self {
+        self.as_std_mut().preserved_fds(fds);
+        self
+    }
+
+    fn preserved_fd_range(&mut self, fds: RangeInclusive<RawFd>, skip_closed: bool) -> &mut Self {
+        self.as_std_mut().preserved_fd_range(fds, skip_closed);
+        self
+    }
+
+    fn close_other_fds(&mut self) -> &mut Self {
+        self.as_std_mut().close_other_fds();
+        self
+    }
+
+    fn compact_fd_mappings(
+        &mut self,
+        fds: Vec<RawFd>,
+        first_child_fd: RawFd,
+    ) -> Result<Vec<FdMapping>, FdMappingError> {
+        self.as_std_mut().compact_fd_mappings(fds, first_child_fd)
+    }
+
+    fn advertised_fd_mappings<K: AsRef<OsStr>>(
+        &mut self,
+        mappings: Vec<(K, RawFd, Option<RawFd>)>,
+        first_child_fd: RawFd,
+    ) -> Result<Vec<FdMapping>, FdMappingError> {
+        self.as_std_mut()
+            .advertised_fd_mappings(mappings, first_child_fd)
+    }
+
+    fn socket_activation_fds(&mut self, fds: Vec<RawFd>) -> Result<&mut Self, FdMappingError> {
+        self.as_std_mut().socket_activation_fds(fds)?;
+        Ok(self)
+    }
+
+    fn socket_activation_fds_with(
+        &mut self,
+        fds: Vec<RawFd>,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<&mut Self, FdMappingError> {
+        self.as_std_mut()
+            .socket_activation_fds_with(fds, mappings)?;
+        Ok(self)
+    }
+
+    fn clear_activation_env(&mut self) -> &mut Self {
+        self.as_std_mut().clear_activation_env();
+        self
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn memfd_to_child(
+        &mut self,
+        data: &[u8],
+        child_fd: RawFd,
+    ) -> Result<&mut Self, FdMappingError> {
+        self.as_std_mut().memfd_to_child(data, child_fd)?;
+        Ok(self)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn eventfd_to_child(
+        &mut self,
+        initial_value: u32,
+        flags: EfdFlags,
+        child_fd: RawFd,
+    ) -> Result<OwnedFd, FdMappingError> {
+        self.as_std_mut()
+            .eventfd_to_child(initial_value, flags, child_fd)
+    }
+
+    fn directory_to_child(
+        &mut self,
+        path: impl AsRef<Path>,
+        child_fd: RawFd,
+    ) -> Result<&mut Self, FdMappingError> {
+        self.as_std_mut().directory_to_child(path, child_fd)?;
+        Ok(self)
+    }
+
+    fn listener_to_child(
+        &mut self,
+        listener: impl Into<OwnedFd>,
+        child_fd: RawFd,
+        nonblocking: bool,
+    ) -> Result<&mut Self, FdMappingError> {
+        self.as_std_mut()
+            .listener_to_child(listener, child_fd, nonblocking)?;
+        Ok(self)
+    }
+
+    fn spawn_blocking_with_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<std::process::Child, FdMappingError> {
+        self.as_std_mut().spawn_blocking_with_fd_mappings(mappings)
+    }
+
+    fn diagnosed_fd_mappings(
+        &mut self,
+        mappings: impl Into<FdMappings>,
+    ) -> Result<MappingDiagnostics, FdMappingError> {
+        self.as_std_mut().diagnosed_fd_mappings(mappings)
+    }
+}
+
+/// A tokio socket type which can be taken out of its runtime to be passed to a child.
+#[cfg(feature = "tokio")]
+pub trait TokioSocket {
+    /// Deregisters the socket from the tokio runtime and returns its FD.
+    fn into_owned_fd(self) -> io::Result<OwnedFd>;
+}
+
+#[cfg(feature = "tokio")]
+macro_rules! impl_tokio_socket {
+    ($($socket:ty),*) => {
+        $(
+            impl TokioSocket for $socket {
+                fn into_owned_fd(self) -> io::Result<OwnedFd> {
+                    Ok(self.into_std()?.into())
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "tokio")]
+impl_tokio_socket!(
+    tokio::net::TcpListener,
+    tokio::net::TcpStream,
+    tokio::net::UdpSocket,
+    tokio::net::UnixDatagram,
+    tokio::net::UnixListener,
+    tokio::net::UnixStream
+);
+
+/// Takes a tokio socket out of its runtime, returning an FD which is ready to be mapped to a child
+/// with [`owned_fd_mappings`](CommandFdExt::owned_fd_mappings).
+///
+/// Mapping the raw FD of a socket which is still registered with tokio leaves the runtime polling
+/// an FD which is now shared with the child, and leaves it in non-blocking mode, which most
+/// programs don't expect to inherit. This deregisters the socket with `into_std` instead, and puts
+/// it into blocking mode unless `nonblocking` is set. The mode is shared with every other copy of
+/// the FD, such as the one the child receives.
+#[cfg(feature = "tokio")]
+pub fn take_raw_fd_for_child(socket: impl TokioSocket, nonblocking: bool) -> io::Result<OwnedFd> {
+    let fd = socket.into_owned_fd()?;
+    set_nonblocking(fd.as_raw_fd(), nonblocking)?;
+    Ok(fd)
+}
+
+/// Adds the given mappings to a command which was built elsewhere, like
+/// [`try_fd_mappings`](CommandFdExt::try_fd_mappings) but as a free function, for generic code
+/// which works with any `&mut Command`.
+///
+/// Problems with the mappings themselves are reported straight away, as an error of kind
+/// [`InvalidInput`](io::ErrorKind::InvalidInput) wrapping the [`FdMappingError`]. Mappings which
+/// were already added to the command are kept, and these are applied after them.
+pub fn apply_fd_mappings<C: CommandFdExt>(
+    command: &mut C,
+    mappings: &[FdMapping],
+) -> io::Result<()> {
+    command.try_fd_mappings(mappings.to_vec())?;
+    Ok(())
+}
+
+/// Normalises the flags of an FD received from another process, such as over a Unix socket with
+/// `SCM_RIGHTS`, so that it is ready to be mapped to a child.
+///
+/// Received FDs only have `FD_CLOEXEC` set if the receiver asked for it with `MSG_CMSG_CLOEXEC`,
+/// and keep whatever `O_NONBLOCK` flag the sender had. This sets `FD_CLOEXEC`, so the FD doesn't
+/// leak into other children spawned meanwhile (mapping it clears the flag on the child's copy
+/// only), and puts it into blocking mode unless `nonblocking` is set.
+pub fn prepare_received_fd(fd: OwnedFd, nonblocking: bool) -> io::Result<OwnedFd> {
+    retry_on_eintr(|| fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)))?;
+    set_nonblocking(fd.as_raw_fd(), nonblocking)?;
+    Ok(fd)
+}
+
+/// Returns whether `fd` has `FD_CLOEXEC` set in this process, so would be closed rather than
+/// inherited when a child is spawned, unless it is mapped or preserved.
+///
+/// Fails with `EBADF` if `fd` isn't open.
+pub fn is_cloexec(fd: RawFd) -> io::Result<bool> {
+    let flags = retry_on_eintr(|| fcntl(fd, FcntlArg::F_GETFD))?;
+    Ok(FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC))
+}
+
+/// Returns the highest FD which mappings should use, given the `RLIMIT_NOFILE` soft limit of this
+/// process, which children inherit.
+///
+/// The checked methods such as [`CommandFdExt::try_fd_mappings`] accept any FD below the limit,
+/// but this leaves room below it for the temporary FDs which mappings may need and for the child
+/// to open FDs of its own, the same headroom which
+/// [`raised_limit_fd_mappings`](CommandFdExt::raised_limit_fd_mappings) leaves. It may be below 3
+/// if the limit is very low.
+pub fn max_usable_fd() -> io::Result<RawFd> {
+    let (soft_limit, _) = nofile_limits()?;
+    let highest = soft_limit.saturating_sub(FD_LIMIT_HEADROOM + 1);
+    Ok(RawFd::try_from(highest).unwrap_or(RawFd::MAX))
+}
+
+/// Returns the soft and hard `RLIMIT_NOFILE` limits of this process. This doesn't allocate, so may
+/// be called from a `pre_exec` hook.
+fn nofile_limits() -> io::Result<(u64, u64)> {
+    Ok(getrlimit(Resource::RLIMIT_NOFILE)?)
+}
+
+/// Creates a command which runs `program` with `LISTEN_PID` set to its own PID, as the systemd
+/// socket activation protocol requires, for use with [`CommandFdExt::socket_activation_fds`].
+///
+/// The command's environment is fixed before the child is forked, so a `pre_exec` hook can't add
+/// the PID to it. Instead, the command runs `/bin/sh`, which sets `LISTEN_PID` to its own PID and
+/// then execs `program` in the same process, passing on any arguments added to the command. The
+/// child's PID is therefore still that of the program, as reported by
+/// [`Child::id`](std::process::Child::id). A tokio command can be created from the result with
+/// `From`.
+pub fn listen_pid_command(program: impl AsRef<OsStr>) -> Command {
+    let mut command = Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(r#"LISTEN_PID=$$ exec "$0" "$@""#)
+        .arg(program);
+    command
+}
+
+/// Creates a command which runs `program` with the given tokio sockets passed to it by the systemd
+/// socket activation protocol, which is what most servers started this way need.
+///
+/// This ties together the lower-level steps: each socket is taken out of its runtime and put into
+/// blocking mode with [`take_raw_fd_for_child`], they are mapped to consecutive FDs from
+/// [`SD_LISTEN_FDS_START`] in the order given, `LISTEN_FDS` is set to how many there are, and
+/// `LISTEN_PID` is set to the child's PID as with [`listen_pid_command`]. Any other activation
+/// variables which this process inherited, such as `LISTEN_FDNAMES`, are removed with
+/// [`clear_activation_env`](CommandFdExt::clear_activation_env). The command owns the sockets until
+/// it is dropped, so drop it once the child is spawned to leave them only to the child.
+///
+/// Add arguments to the returned command as usual. A tokio command can be created from it with
+/// `From`.
+#[cfg(feature = "tokio")]
+pub fn socket_activated_command<S: TokioSocket>(
+    program: impl AsRef<OsStr>,
+    sockets: impl IntoIterator<Item = S>,
+) -> Result<Command, FdMappingError> {
+    let fds = sockets
+        .into_iter()
+        .map(|socket| take_raw_fd_for_child(socket, false))
+        .collect::<io::Result<Vec<_>>>()?;
+    let count = fds.len();
+    let mut command = listen_pid_command(program);
+    command.owned_fd_mappings(fds.into_iter().zip(SD_LISTEN_FDS_START..).collect())?;
+    command
+        .env("LISTEN_FDS", count.to_string())
+        .clear_activation_env();
+    Ok(command)
+}
+
+/// The first FD passed by the systemd socket activation protocol.
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// How far above the highest FD the mappings need to raise the `RLIMIT_NOFILE` soft limit, so that
+/// the child can still open more FDs of its own afterwards.
+const FD_LIMIT_HEADROOM: u64 = 64;
+
+/// The FD below which [`CommandFdExt::close_other_fds`] sets `FD_CLOEXEC` one by one when it has
+/// no way to find out which FDs are open, however high the `RLIMIT_NOFILE` soft limit is. This is
+/// Linux's default for the highest possible limit.
+const MAX_CHECKED_FDS: RawFd = 1 << 20;
+
+/// The environment variables removed by [`CommandFdExt::clear_activation_env`].
+const ACTIVATION_ENV_VARS: &[&str] = &[
+    "LISTEN_FDS",
+    "LISTEN_PID",
+    "LISTEN_FDNAMES",
+    "NOTIFY_SOCKET",
+];
+
+/// Set in the child process once any FDs have been passed on by mappings or preserved FDs, so that
+/// a `close_other_fds` hook registered too late can detect that it would undo them.
+///
+/// This is only ever set by `pre_exec` hooks, so it is always false in the parent and hence starts
+/// out false in every forked child.
+static FDS_PASSED: AtomicBool = AtomicBool::new(false);
+
+/// Works out the child FD for each mapping which doesn't have one, as described for
+/// [`CommandFdExt::assigned_fd_mappings`].
+fn assign_child_fds(mappings: &[(RawFd, Option<RawFd>)], first_child_fd: RawFd) -> Vec<FdMapping> {
+    let mut explicit_fds: Vec<RawFd> = mappings
+        .iter()
+        .filter_map(|(_, child_fd)| *child_fd)
+        .collect();
+    explicit_fds.sort_unstable();
+
+    let mut next_fd = first_child_fd;
+    mappings
+        .iter()
+        .map(|&(parent_fd, child_fd)| {
+            let child_fd = child_fd.unwrap_or_else(|| {
+                while explicit_fds.binary_search(&next_fd).is_ok() {
+                    next_fd += 1;
+                }
+                let assigned_fd = next_fd;
+                next_fd += 1;
+                assigned_fd
+            });
+            FdMapping::new(parent_fd, child_fd)
+        })
+        .collect()
+}
+
+/// Returns the child FDs of the given mappings, in ascending order.
+fn sorted_child_fds(mappings: &[FdMapping]) -> Vec<RawFd> {
+    let mut child_fds: Vec<RawFd> = mappings.iter().map(|mapping| mapping.child_fd).collect();
+    child_fds.sort_unstable();
+    child_fds
+}
+
+/// Returns the lowest child FD which two or more of the given mappings target, if any.
+fn find_duplicate_child_fd(mappings: &[FdMapping]) -> Option<RawFd> {
+    sorted_child_fds(mappings)
+        .windows(2)
+        .find(|pair| pair[0] == pair[1])
+        .map(|pair| pair[0])
+}
+
+/// Records the plan for a set of mappings, as nothing can be logged from the child once it applies
+/// it.
+#[cfg(feature = "tracing")]
+fn trace_plan(plan: &MappingPlan) {
+    /// Programs using `select(2)` can't handle FDs from here upwards.
+    const FD_SETSIZE: RawFd = 1024;
+
+    tracing::debug!(mappings = ?plan.mappings, ops = ?plan.ops, "Planned FD mappings");
+    if plan.temporary_count > 0 {
+        tracing::debug!(
+            temporaries = plan.temporary_count,
+            first_temporary_fd = plan.first_safe_fd,
+            "FD mappings form cycles, so need temporary FDs"
+        );
+    }
+    let highest_child_fd = plan
+        .ops
+        .iter()
+        .filter_map(|op| match *op {
+            FdOp::Dup2 { to, .. } | FdOp::Dup2FromTemporary { to, .. } => Some(to),
+            FdOp::ClearCloexec { fd } | FdOp::SetNonblocking { fd, .. } | FdOp::Seek { fd, .. } => {
+                Some(fd)
+            }
+            FdOp::DupToTemporary { .. } | FdOp::Close { .. } | FdOp::CloseTemporary { .. } => None,
+        })
+        .max();
+    for mapping in plan
+        .mappings
+        .iter()
+        .filter(|mapping| mapping.child_fd <= 2 && mapping.parent_fd != mapping.child_fd)
+    {
+        let fd = mapping.child_fd;
+        if mapping.stdio_policy == StdioPolicy::Warn {
+            tracing::warn!(
+                fd,
+                "FD mappings replace a stdio FD, overriding the command's stdio configuration for it"
+            );
+        } else {
+            tracing::debug!(
+                fd,
+                "FD mappings replace a stdio FD, overriding the command's stdio configuration for it"
+            );
+        }
+    }
+    if let Some(fd) = highest_child_fd.filter(|&fd| fd >= FD_SETSIZE) {
+        tracing::warn!(
+            fd,
+            "FD mappings pass an FD above FD_SETSIZE, which the child can't use with select(2)"
+        );
+    }
+}
+
+/// Checks that no mapping replaces a stdio FD unless its policy allows it.
+fn check_stdio_targets(mappings: &[FdMapping]) -> Result<(), FdMappingError> {
+    match mappings.iter().find(|mapping| {
+        mapping.child_fd <= 2
+            && mapping.parent_fd != mapping.child_fd
+            && mapping.stdio_policy == StdioPolicy::Error
+    }) {
+        Some(mapping) => Err(FdMappingError::StdioTarget(mapping.child_fd)),
+        None => Ok(()),
+    }
+}
+
+/// Checks that none of the FDs in the given mappings are negative, and that their seek offsets are
+/// in range.
+fn validate_fds(mappings: &[FdMapping]) -> Result<(), FdMappingError> {
+    if let Some(fd) = mappings
+        .iter()
+        .flat_map(|mapping| [mapping.parent_fd, mapping.child_fd])
+        .find(|fd| *fd < 0)
+    {
+        return Err(FdMappingError::BadFd(fd));
+    }
+    match mappings.iter().find_map(|mapping| {
+        let position = mapping.seek?;
+        seek_args(position)
+            .is_none()
+            .then_some((mapping.child_fd, position))
+    }) {
+        Some((child_fd, position)) => Err(FdMappingError::SeekOutOfRange { child_fd, position }),
+        None => Ok(()),
+    }
+}
+
+/// Converts a seek position to the arguments for `lseek`, or returns None if the offset doesn't fit
+/// in an `off_t`.
+fn seek_args(position: SeekPosition) -> Option<(off_t, Whence)> {
+    match position {
+        SeekPosition::Start(offset) => Some((off_t::try_from(offset).ok()?, Whence::SeekSet)),
+        SeekPosition::End(offset) => Some((off_t::try_from(offset).ok()?, Whence::SeekEnd)),
+    }
+}
+
+/// Returns the FD flags of the given FD, or `None` if it isn't open.
+fn fd_flags(fd: RawFd) -> io::Result<Option<FdFlag>> {
+    if fd < 0 {
+        return Ok(None);
+    }
+    match retry_on_eintr(|| fcntl(fd, FcntlArg::F_GETFD)) {
+        Ok(flags) => Ok(Some(FdFlag::from_bits_truncate(flags))),
+        Err(Errno::EBADF) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Sets or clears `O_NONBLOCK` on the given FD.
+fn set_nonblocking(fd: RawFd, nonblocking: bool) -> io::Result<()> {
+    Ok(set_nonblocking_flag(fd, nonblocking)?)
+}
+
+/// Sets or clears `O_NONBLOCK` on the given FD, leaving its other status flags alone. This only
+/// makes syscalls, so is safe to call from a `pre_exec` hook.
+fn set_nonblocking_flag(fd: RawFd, nonblocking: bool) -> nix::Result<()> {
+    let mut flags = OFlag::from_bits_truncate(retry_on_eintr(|| fcntl(fd, FcntlArg::F_GETFL))?);
+    flags.set(OFlag::O_NONBLOCK, nonblocking);
+    retry_on_eintr(|| fcntl(fd, FcntlArg::F_SETFL(flags)))?;
+    Ok(())
+}
+
+/// Creates a pipe with `FD_CLOEXEC` set on both ends, returning the read and write ends.
+fn cloexec_pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC)?;
+    #[cfg(not(any(target_os = "android", target_os = "linux")))]
+    let (read_end, write_end) = pipe()?;
+    // Safety: pipe2 and pipe return newly opened FDs, which nothing else owns.
+    let (read_end, write_end) = unsafe {
+        (
+            OwnedFd::from_raw_fd(read_end),
+            OwnedFd::from_raw_fd(write_end),
+        )
+    };
+    #[cfg(not(any(target_os = "android", target_os = "linux")))]
+    for fd in [&read_end, &write_end] {
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    }
+    Ok((read_end, write_end))
+}
+
+/// Checks that every parent FD in the given mappings is currently open in this process.
+fn check_parent_fds_open(mappings: &[FdMapping]) -> Result<(), FdMappingError> {
+    for mapping in mappings {
+        retry_on_eintr(|| fcntl(mapping.parent_fd, FcntlArg::F_GETFD))
+            .map_err(|e| FdMappingError::from_errno(e, mapping.parent_fd))?;
+    }
+    Ok(())
+}
+
+/// Validates the given mappings and works out the plan to apply them, checking everything which can
+/// be checked before spawning. The parent FDs are only checked to be open if `check_open` is set.
+fn checked_plan(mappings: &[FdMapping], check_open: bool) -> Result<MappingPlan, FdMappingError> {
+    validate_fds(mappings)?;
+    let plan = MappingPlan::new(mappings)?;
+    plan.check_fd_limit()?;
+    if check_open {
+        check_parent_fds_open(mappings)?;
+    }
+    Ok(plan)
+}
+
+/// Splits owned FDs to be mapped to the given child FDs into the FDs and the mappings.
+fn split_owned_mappings(mappings: Vec<(OwnedFd, RawFd)>) -> (Vec<OwnedFd>, Vec<FdMapping>) {
+    mappings
+        .into_iter()
+        .map(|(fd, child_fd)| {
+            let parent_fd = fd.as_raw_fd();
+            (fd, FdMapping::new(parent_fd, child_fd))
+        })
+        .unzip()
+}
+
+/// Registers a `pre_exec` hook on the command to apply the given plan. The hook also takes
+/// ownership of `owned_fds`, so they stay open for as long as the command exists.
+fn register_plan(
+    command: &mut Command,
+    plan: MappingPlan,
+    owned_fds: Vec<OwnedFd>,
+) -> &mut Command {
+    register_prepared(command, PreparedFdMappings::from_plan(plan), owned_fds)
+}
+
+/// Like [`register_plan`], but for mappings which have already been prepared.
+fn register_prepared(
+    command: &mut Command,
+    mut prepared: PreparedFdMappings,
+    owned_fds: Vec<OwnedFd>,
+) -> &mut Command {
+    // Register the callback to apply the mappings after forking but before execing.
+    // Safety: `map_fds` will not allocate, so it is safe to call from this hook, which only ever
+    // runs in the forked child.
+    unsafe {
+        command.pre_exec(move || {
+            // Refer to the FDs so that they are moved into the closure and kept open with it.
+            let _ = &owned_fds;
+            prepared.map_fds()
+        });
+    }
+
+    command
+}
+
+/// A set of FD mappings which has been validated and planned in the parent, ready to be applied
+/// from a `pre_exec` hook of your own.
+///
+/// This is for when the mappings need to be applied at a particular point among other things done
+/// between fork and exec, such as calling `setsid`, rather than in a hook of their own. Otherwise
+/// prefer the methods of [`CommandFdExt`], which register the hook themselves.
+///
+/// Everything which needs to allocate is done by [`new`](Self::new), so that
+/// [`map_fds`](Self::map_fds) only makes syscalls.
+#[derive(Clone, Debug)]
+pub struct PreparedFdMappings {
+    plan: MappingPlan,
+    /// The temporary FDs are stored here by the child, so that applying the plan doesn't need to
+    /// allocate. This is only ever written in a forked child, which writes to its own copy.
+    temporaries: Vec<RawFd>,
+    /// FDs opened in the parent to hold the numbers which the temporary FDs will take in the child,
+    /// so that the child doesn't need to find free FDs of its own. These are shared between clones.
+    reserved: Arc<Vec<OwnedFd>>,
+    /// Where to record which step failed, for [`MappingDiagnostics`].
+    failure: Option<Arc<FailureSlot>>,
+}
+
+impl PreparedFdMappings {
+    /// Validates the given mappings and works out how to apply them, reporting every problem which
+    /// can be detected before spawning, like [`CommandFdExt::try_fd_mappings`].
+    ///
+    /// The parent FDs must be kept open until after the child is spawned.
+    pub fn new(mappings: &[FdMapping]) -> Result<Self, FdMappingError> {
+        check_stdio_targets(mappings)?;
+        Ok(Self::from_plan(checked_plan(mappings, true)?))
+    }
+
+    /// Like [`new`](Self::new), but doesn't check that the parent FDs are open yet, like
+    /// [`CommandFdExt::deferred_fd_mappings`]. This allows mapping FDs which are only opened by an
+    /// earlier step of the `pre_exec` hook.
+    pub fn new_deferred(mappings: &[FdMapping]) -> Result<Self, FdMappingError> {
+        check_stdio_targets(mappings)?;
+        Ok(Self::from_plan(checked_plan(mappings, false)?))
+    }
+
+    fn from_plan(plan: MappingPlan) -> Self {
+        #[cfg(feature = "tracing")]
+        trace_plan(&plan);
+        let temporaries = vec![-1; plan.temporary_count];
+        let reserved = Arc::new(reserve_temporaries(&plan));
+        Self {
+            plan,
+            temporaries,
+            reserved,
+            failure: None,
+        }
+    }
+
+    /// Applies the mappings to the current process. This doesn't allocate, so is safe to call from
+    /// a `pre_exec` hook.
+    ///
+    /// Only the OS error code of any error is returned, as that is all `pre_exec` can report back to
+    /// the parent anyway.
+    ///
+    /// Any FDs which the mappings should override, such as those opened by an earlier step of the
+    /// hook, must be opened before calling this. Conversely, anything which closes FDs or sets
+    /// `FD_CLOEXEC` on them, such as [`CommandFdExt::close_other_fds`], must happen before calling
+    /// this, as it would otherwise undo the mappings. Temporary FDs are only ever taken from FD
+    /// numbers which were reserved for them by [`new`](Self::new) or which are unused at the time,
+    /// and are closed again before this returns.
+    ///
+    /// # Safety
+    ///
+    /// This must only be called in a forked child process before it execs, such as from a
+    /// [`pre_exec`](CommandExt::pre_exec) hook. Otherwise it would replace FDs which other code in
+    /// the process owns.
+    pub unsafe fn map_fds(&mut self) -> io::Result<()> {
+        FDS_PASSED.store(true, Ordering::SeqCst);
+        self.plan
+            .apply(
+                &mut self.temporaries,
+                &self.reserved,
+                self.failure.as_deref(),
+            )
+            .map_err(FdMappingError::into_raw_os_error)
+    }
+
+    /// Returns the steps which [`map_fds`](Self::map_fds) will take to apply the mappings, in
+    /// order.
+    ///
+    /// The same steps are taken by the hooks which the [`CommandFdExt`] methods register, so this
+    /// can be used to check what those will do for a given set of mappings.
+    pub fn planned_fd_operations(&self) -> &[FdOp] {
+        &self.plan.ops
+    }
+
+    /// Returns the mappings which the plan was worked out from, including their labels, sorted into
+    /// the order described for [`FdMapping`].
+    pub fn mappings(&self) -> &[FdMapping] {
+        &self.plan.mappings
+    }
+
+    /// Returns the mappings which are part of a cycle, such as two mappings swapping FDs, and so
+    /// read their parent FD from a temporary FD instead.
+    ///
+    /// Each temporary FD costs an extra `dup` and `close` in the child, so choosing child FDs which
+    /// don't form cycles avoids these. An empty slice means that no temporary FDs are needed.
+    pub fn conflicts(&self) -> &[FdMapping] {
+        &self.plan.conflicts
+    }
+
+    /// Returns the lowest FD which may be used for temporary FDs, which by default is higher than
+    /// every parent and child FD of the mappings. Each temporary FD is one which was reserved at or
+    /// above this in the parent, or failing that the lowest one at or above this which is unused in
+    /// the child at the time.
+    pub fn first_temporary_fd(&self) -> RawFd {
+        self.plan.first_safe_fd
+    }
+
+    /// Sets the lowest FD to use for temporary FDs, for environments which reserve a particular
+    /// range of FDs for scratch use.
+    ///
+    /// This must be higher than every child FD of the mappings, so that a temporary FD can't take a
+    /// child FD before it has been mapped, but may be lower than parent FDs. Those stay open while
+    /// the mappings are applied, so are never picked for temporary FDs. Like the other checks, this
+    /// also fails if the temporary FDs which may be needed would be too high for the
+    /// `RLIMIT_NOFILE` soft limit.
+    pub fn with_first_temporary_fd(mut self, fd: RawFd) -> Result<Self, FdMappingError> {
+        self.plan.set_first_temporary_fd(fd)?;
+        self.plan.check_fd_limit()?;
+        // Release the old reservation first, so that the new one can reuse its FDs if they fit.
+        self.reserved = Arc::new(Vec::new());
+        self.reserved = Arc::new(reserve_temporaries(&self.plan));
+        Ok(self)
+    }
+}
+
+/// Reports which mapping failed when a command with mappings added by
+/// [`CommandFdExt::diagnosed_fd_mappings`] failed to spawn.
+///
+/// The child can't allocate or send anything back before exec apart from an OS error code, so it
+/// records which step of the plan failed in a small piece of memory shared with the parent, which
+/// this reads back. Each spawn overwrites the record, so it only describes the most recent one.
+#[derive(Clone, Debug)]
+pub struct MappingDiagnostics {
+    slot: Arc<FailureSlot>,
+    ops: Vec<FdOp>,
+    mappings: Vec<FdMapping>,
+}
+
+impl MappingDiagnostics {
+    /// Returns an [`FdMappingError::MappingFailed`] for the mapping which failed when the command
+    /// was last spawned, and clears the record. Returns `None` if these mappings were applied
+    /// successfully, or weren't reached because something else failed first.
+    pub fn take_failure(&self) -> Option<FdMappingError> {
+        let (index, errno) = self.slot.take()?;
+        let (parent_fd, child_fd) = op_mapping(&self.ops, index);
+        let label = self
+            .mappings
+            .iter()
+            .find(|mapping| mapping.child_fd == child_fd)
+            .and_then(|mapping| mapping.label.clone());
+        Some(FdMappingError::MappingFailed {
+            parent_fd,
+            child_fd,
+            label,
+            source: io::Error::from_raw_os_error(errno),
+        })
+    }
+}
+
+/// A record of what each parent FD of a set of mappings refers to, for checking after spawning that
+/// none of them were closed or replaced in the parent.
+///
+/// The mappings are only ever applied to the child's own copy of the FD table, so this should never
+/// fail. It is a cheap safety net against that regressing. The check compares the device and inode
+/// numbers of each FD, so it also notices an FD which was closed and then reused for another file.
+/// Another thread closing or reopening one of the FDs while the child is spawned would also make
+/// it fail, so only use it where nothing else touches them.
+#[derive(Clone, Debug)]
+pub struct ParentFdSnapshot {
+    fds: Vec<(RawFd, libc::dev_t, libc::ino_t)>,
+}
+
+impl ParentFdSnapshot {
+    /// Records what each parent FD of the given mappings refers to. Fails with
+    /// [`FdMappingError::BadFd`] if any of them isn't open.
+    pub fn new(mappings: &[FdMapping]) -> Result<Self, FdMappingError> {
+        let mut parent_fds: Vec<RawFd> = mappings.iter().map(|mapping| mapping.parent_fd).collect();
+        parent_fds.sort_unstable();
+        parent_fds.dedup();
+        let fds = parent_fds
+            .into_iter()
+            .map(|fd| {
+                let stat = fstat(fd).map_err(|e| FdMappingError::from_errno(e, fd))?;
+                Ok((fd, stat.st_dev, stat.st_ino))
+            })
+            .collect::<Result<_, FdMappingError>>()?;
+        Ok(Self { fds })
+    }
+
+    /// Checks that every parent FD is still open and refers to the same file as when the snapshot
+    /// was taken, failing with [`FdMappingError::BadFd`] for the first one which doesn't.
+    pub fn check(&self) -> Result<(), FdMappingError> {
+        for &(fd, dev, ino) in &self.fds {
+            match fstat(fd) {
+                Ok(stat) if stat.st_dev == dev && stat.st_ino == ino => {}
+                _ => return Err(FdMappingError::BadFd(fd)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Works out the parent and child FD of the mapping which the step at `index` of a plan is part of,
+/// or -1 for either of them which can't be found.
+fn op_mapping(ops: &[FdOp], index: usize) -> (RawFd, RawFd) {
+    // The FD saved in a temporary slot, which is the parent FD of the mappings reading it.
+    let temporary_source = |slot: usize| {
+        ops.iter().find_map(|op| match *op {
+            FdOp::DupToTemporary { fd, temporary } if temporary == slot => Some(fd),
+            _ => None,
+        })
+    };
+    // The parent FD of the mapping to the given child FD.
+    let source_of = |child_fd: RawFd| {
+        ops.iter().find_map(|op| match *op {
+            FdOp::Dup2 { from, to } if to == child_fd => Some(from),
+            FdOp::Dup2FromTemporary { temporary, to } if to == child_fd => {
+                temporary_source(temporary)
+            }
+            FdOp::ClearCloexec { fd } if fd == child_fd => Some(fd),
+            _ => None,
+        })
+    };
+    let (parent_fd, child_fd) = match ops[index] {
+        FdOp::Dup2 { from, to } => (Some(from), Some(to)),
+        FdOp::Dup2FromTemporary { temporary, to } => (temporary_source(temporary), Some(to)),
+        FdOp::DupToTemporary { temporary, .. } | FdOp::CloseTemporary { temporary } => (
+            temporary_source(temporary),
+            ops.iter().find_map(|op| match *op {
+                FdOp::Dup2FromTemporary {
+                    temporary: slot,
+                    to,
+                } if slot == temporary => Some(to),
+                _ => None,
+            }),
+        ),
+        FdOp::ClearCloexec { fd } => (Some(fd), Some(fd)),
+        FdOp::SetNonblocking { fd, .. } | FdOp::Seek { fd, .. } => (source_of(fd), Some(fd)),
+        FdOp::Close { fd } => (
+            Some(fd),
+            ops.iter().find_map(|op| match *op {
+                FdOp::Dup2 { from, to } if from == fd => Some(to),
+                _ => None,
+            }),
+        ),
+    };
+    (parent_fd.unwrap_or(-1), child_fd.unwrap_or(-1))
+}
+
+/// A record of which step of a plan failed and why, in memory shared with forked children so that
+/// they can write it without allocating.
+#[derive(Debug)]
+struct FailureSlot {
+    ptr: NonNull<FailureRecord>,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct FailureRecord {
+    /// One more than the index of the failed step, or 0 if nothing has failed.
+    op: AtomicUsize,
+    errno: AtomicI32,
+}
+
+// Safety: the record is only accessed through atomics, and only unmapped when the slot is dropped.
+unsafe impl Send for FailureSlot {}
+unsafe impl Sync for FailureSlot {}
+
+impl FailureSlot {
+    fn new() -> io::Result<Self> {
+        let length = NonZeroUsize::new(size_of::<FailureRecord>()).unwrap();
+        // Safety: this maps new anonymous memory, which doesn't alias anything else. It is zeroed,
+        // which is a valid FailureRecord with nothing recorded.
+        let record = unsafe {
+            mmap(
+                None,
+                length,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED | MapFlags::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        }
+        .map_err(io::Error::from)?;
+        Ok(Self {
+            ptr: NonNull::new(record.cast()).unwrap(),
+        })
+    }
+
+    fn record(&self) -> &FailureRecord {
+        // Safety: the memory stays mapped for as long as self exists.
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// Clears the record. This doesn't allocate, so may be called from a `pre_exec` hook.
+    fn clear(&self) {
+        self.record().op.store(0, Ordering::SeqCst);
+    }
+
+    /// Records that the step at `index` failed with `errno`. This doesn't allocate, so may be
+    /// called from a `pre_exec` hook.
+    fn record_failure(&self, index: usize, errno: i32) {
+        self.record().errno.store(errno, Ordering::SeqCst);
+        self.record().op.store(index + 1, Ordering::SeqCst);
+    }
+
+    /// Returns the recorded failed step and error, if any, and clears the record.
+    fn take(&self) -> Option<(usize, i32)> {
+        let op = self.record().op.swap(0, Ordering::SeqCst);
+        let index = op.checked_sub(1)?;
+        Some((index, self.record().errno.load(Ordering::SeqCst)))
+    }
+}
+
+impl Drop for FailureSlot {
+    fn drop(&mut self) {
+        // Safety: the memory was mapped by new with this length, and nothing refers to it any more.
+        // There is nothing useful to do if unmapping fails.
+        let _ = unsafe { munmap(self.ptr.as_ptr().cast(), size_of::<FailureRecord>()) };
+    }
+}
+
+/// The parent's copies of FDs passed to a child by [`CommandFdExt::guarded_fd_mappings`], which are
+/// closed when this is dropped.
+///
+/// The FDs can't be accessed through this, so once they have been handed over to the child there is
+/// no way to accidentally keep using them in the parent.
+#[derive(Debug)]
+pub struct PassedFds {
+    state: Arc<PassedFdsState>,
+}
+
+#[derive(Debug)]
+struct PassedFdsState {
+    /// Set before the FDs are closed, so that a child forked afterwards knows not to map them.
+    closed: AtomicBool,
+    fds: Mutex<Vec<OwnedFd>>,
+}
+
+impl PassedFds {
+    fn new(fds: Vec<OwnedFd>) -> Self {
+        Self {
+            state: Arc::new(PassedFdsState {
+                closed: AtomicBool::new(false),
+                fds: Mutex::new(fds),
+            }),
+        }
+    }
+
+    /// Closes the parent's copies of the FDs. This is the same as dropping the guard, but makes the
+    /// intent clearer.
+    pub fn close(self) {}
+}
+
+impl Drop for PassedFds {
+    fn drop(&mut self) {
+        self.state.closed.store(true, Ordering::SeqCst);
+        // The lock can only be poisoned by a panic while it is held here, which can't happen.
+        if let Ok(mut fds) = self.state.fds.lock() {
+            fds.clear();
+        }
+    }
+}
+
+/// A handle to mappings added by [`CommandFdExt::clearable_fd_mappings`], which can clear them from
+/// the command.
+#[derive(Clone, Debug)]
+pub struct FdMappingsHandle {
+    /// Set once the mappings are cleared, so that a child forked afterwards knows not to apply them.
+    cleared: Arc<AtomicBool>,
+}
+
+impl FdMappingsHandle {
+    /// Clears the mappings from the command, so that children spawned from it from now on don't
+    /// get them. Their parent FDs are then only passed on if they don't have `FD_CLOEXEC` set.
+    pub fn clear_fd_mappings(&self) {
+        self.cleared.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns whether the mappings have been cleared.
+    pub fn is_cleared(&self) -> bool {
+        self.cleared.load(Ordering::SeqCst)
+    }
+}
+
+/// A command with mappings from borrowed FDs, returned by [`CommandFdExt::borrowed_fd_mappings`].
+///
+/// This borrows both the command and the FDs, and derefs to the command so that it can be spawned
+/// while the FDs are known to be open. Once this is dropped, spawning the command fails with
+/// `EBADF`.
+#[derive(Debug)]
+pub struct BorrowedFdCommand<'a, C> {
+    command: &'a mut C,
+    /// Set when this is dropped, so that a child forked afterwards knows not to map the FDs.
+    released: Arc<AtomicBool>,
+}
+
+impl<C> Deref for BorrowedFdCommand<'_, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.command
+    }
+}
+
+impl<C> DerefMut for BorrowedFdCommand<'_, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.command
+    }
+}
+
+impl<C> Drop for BorrowedFdCommand<'_, C> {
+    fn drop(&mut self) {
+        self.released.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Registers a hook for [`CommandFdExt::borrowed_fd_mappings`], returning the flag which stops it
+/// from mapping the FDs once they are no longer borrowed.
+fn register_borrowed<'a>(
+    command: &mut Command,
+    mappings: impl IntoIterator<Item = (BorrowedFd<'a>, RawFd)>,
+) -> Result<Arc<AtomicBool>, FdMappingError> {
+    let fd_mappings: Vec<FdMapping> = mappings
+        .into_iter()
+        .map(|(fd, child_fd)| FdMapping::new(fd.as_raw_fd(), child_fd))
+        .collect();
+    // The FDs are borrowed, so they must be open.
+    let mut prepared = PreparedFdMappings::from_plan(checked_plan(&fd_mappings, false)?);
+    let released = Arc::new(AtomicBool::new(false));
+    let state = released.clone();
+
+    // Safety: `map_fds` will not allocate, so it is safe to call from this hook, which only ever
+    // runs in the forked child.
+    unsafe {
+        command.pre_exec(move || {
+            if state.load(Ordering::SeqCst) {
+                return Err(Errno::EBADF.into());
+            }
+            prepared.map_fds()
+        });
+    }
+
+    Ok(released)
+}
+
+/// A single step in applying a set of FD mappings in the child process, as returned by
+/// [`PreparedFdMappings::planned_fd_operations`].
+///
+/// The exact number of a temporary FD depends on which FDs are open in the child, so temporaries
+/// are identified by slot numbers from 0 upwards instead.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum FdOp {
+    /// Duplicates `fd` to a new temporary FD at or above
+    /// [`first_temporary_fd`](PreparedFdMappings::first_temporary_fd), with `FD_CLOEXEC` set, and
+    /// stores it in the given temporary slot. This is the FD which the parent reserved for the slot
+    /// if there is one, or otherwise the lowest unused one.
+    DupToTemporary { fd: RawFd, temporary: usize },
+    /// Duplicates `from` to `to`, closing whatever `to` was before and clearing `FD_CLOEXEC` on it.
+    Dup2 { from: RawFd, to: RawFd },
+    /// Duplicates the FD in the given temporary slot to `to`.
+    Dup2FromTemporary { temporary: usize, to: RawFd },
+    /// Clears `FD_CLOEXEC` on `fd`, so it is kept open when exec is called for the child.
+    ClearCloexec { fd: RawFd },
+    /// Sets or clears `O_NONBLOCK` on `fd`, and so on every other FD which shares its open file
+    /// description.
+    SetNonblocking { fd: RawFd, nonblocking: bool },
+    /// Moves the offset of `fd`, and so of every other FD which shares its open file description.
+    Seek { fd: RawFd, position: SeekPosition },
+    /// Closes `fd`, a parent FD which a mapping moved to its child FD.
+    Close { fd: RawFd },
+    /// Closes the FD in the given temporary slot.
+    CloseTemporary { temporary: usize },
+}
+
+/// The steps needed to apply a set of FD mappings, worked out in the parent so that the child needs
+/// only to make the corresponding syscalls.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct MappingPlan {
+    ops: Vec<FdOp>,
+    /// The first FD which may be used as a temporary FD. By default this is higher than any parent
+    /// or child FD in the mappings, but it may be set lower, as long as it is above every child FD.
+    first_safe_fd: RawFd,
+    /// The highest child FD of the mappings, or -1 if there are no mappings.
+    highest_child_fd: RawFd,
+    /// The number of temporary slots which `ops` refers to.
+    temporary_count: usize,
+    /// The mappings which the plan was worked out from, sorted.
+    mappings: Vec<FdMapping>,
+    /// The mappings which read from a temporary slot rather than their parent FD, sorted.
+    conflicts: Vec<FdMapping>,
+    /// The highest FD which the plan may need to use in the child, whether a child FD or a
+    /// temporary, or -1 if there are no mappings.
+    highest_fd: RawFd,
+    /// The soft and hard `RLIMIT_NOFILE` limits to set in the child before applying the plan, if
+    /// the soft limit needs to be raised to allow `highest_fd`.
+    raised_fd_limit: Option<(u64, u64)>,
+}
+
+impl MappingPlan {
+    fn new(mappings: &[FdMapping]) -> Result<Self, FdMappingCollision> {
+        // Validate that there are no conflicting mappings to the same child FD.
+        if let Some(child_fd) = find_duplicate_child_fd(mappings) {
+            return Err(FdMappingCollision { child_fd });
+        }
+        // Work from a canonical order, so that the plan only depends on the set of mappings.
+        let mut sorted = mappings.to_vec();
+        sorted.sort_unstable();
+        let mappings = &sorted[..];
+        let child_fds = sorted_child_fds(mappings);
+        // A kept parent FD can't also be overwritten by another mapping.
+        if let Some(mapping) = mappings.iter().find(|kept| {
+            kept.keep_source
+                && mappings.iter().any(|mapping| {
+                    mapping.child_fd == kept.parent_fd && mapping.parent_fd != mapping.child_fd
+                })
+        }) {
+            return Err(FdMappingCollision {
+                child_fd: mapping.parent_fd,
+            });
+        }
+
+        // There may be other files open at or above this in the child, so we still need to ensure
+        // we don't conflict with them, but F_DUPFD_CLOEXEC takes care of that.
+        let first_safe_fd = mappings
+            .iter()
+            .map(|mapping| max(mapping.parent_fd, mapping.child_fd) + 1)
+            .max()
+            .unwrap_or(0);
+
+        // Mappings to the same FD don't move anything, we can handle them by just removing the
+        // FD_CLOEXEC flag from the existing (parent) FD. Every other mapping overwrites its child
+        // FD, so it must wait until every mapping which reads the old FD there has been applied.
+        // The child FDs are unique, so each FD is overwritten by at most one mapping, its writer.
+        let mut writers: Vec<(RawFd, usize)> = mappings
+            .iter()
+            .enumerate()
+            .filter(|(_, mapping)| mapping.parent_fd != mapping.child_fd)
+            .map(|(index, mapping)| (mapping.child_fd, index))
+            .collect();
+        writers.sort_unstable();
+        let writer_of = |fd: RawFd| {
+            writers
+                .binary_search_by_key(&fd, |&(child_fd, _)| child_fd)
+                .ok()
+        };
+
+        // The number of mappings still to be applied which read the FD each writer overwrites.
+        let mut pending_readers = vec![0; writers.len()];
+        for &(_, index) in &writers {
+            if let Some(writer) = writer_of(mappings[index].parent_fd) {
+                pending_readers[writer] += 1;
+            }
+        }
+
+        // By default each mapping needs a dup2 and a change to its blocking mode.
+        let mut ops = Vec::with_capacity(2 * mappings.len());
+        let mut done = vec![false; writers.len()];
+        // The temporary slot each mapping reads from instead of its parent FD, if any.
+        let mut temporary_slots = vec![None; mappings.len()];
+        let mut temporary_count = 0;
+        let mut ready: VecDeque<usize> = mappings
+            .iter()
+            .filter_map(|mapping| writer_of(mapping.child_fd))
+            .filter(|&writer| pending_readers[writer] == 0)
+            .collect();
+        loop {
+            while let Some(writer) = ready.pop_front() {
+                let index = writers[writer].1;
+                let mapping = &mappings[index];
+                done[writer] = true;
+                if let Some(temporary) = temporary_slots[index] {
+                    ops.push(FdOp::Dup2FromTemporary {
+                        temporary,
+                        to: mapping.child_fd,
+                    });
+                } else {
+                    ops.push(FdOp::Dup2 {
+                        from: mapping.parent_fd,
+                        to: mapping.child_fd,
+                    });
+                    if let Some(source_writer) = writer_of(mapping.parent_fd) {
+                        pending_readers[source_writer] -= 1;
+                        if pending_readers[source_writer] == 0 {
+                            ready.push_back(source_writer);
+                        }
+                    }
+                }
+            }
+
+            // Any mappings left are waiting on each other in cycles, such as a swap. Break the
+            // first one by saving the FD it overwrites to a temporary FD which is clear of either
+            // range, and having its readers read that instead.
+            let stuck = mappings
+                .iter()
+                .filter_map(|mapping| writer_of(mapping.child_fd))
+                .find(|&writer| !done[writer]);
+            let writer = match stuck {
+                Some(writer) => writer,
+                None => break,
+            };
+            let fd = mappings[writers[writer].1].child_fd;
+            ops.push(FdOp::DupToTemporary {
+                fd,
+                temporary: temporary_count,
+            });
+            // Readers which have already been applied read the FD directly, before it was replaced.
+            for (reader, &(_, index)) in writers.iter().enumerate() {
+                if !done[reader]
+                    && mappings[index].parent_fd == fd
+                    && temporary_slots[index].is_none()
+                {
+                    temporary_slots[index] = Some(temporary_count);
+                }
+            }
+            temporary_count += 1;
+            pending_readers[writer] = 0;
+            ready.push_back(writer);
+        }
+
+        // Identity mappings and kept parent FDs just need FD_CLOEXEC cleared where they are. The
+        // mappings are sorted by parent FD, so duplicates are adjacent.
+        let mut kept: Vec<RawFd> = mappings
+            .iter()
+            .filter(|mapping| mapping.parent_fd == mapping.child_fd || mapping.keep_source)
+            .map(|mapping| mapping.parent_fd)
+            .collect();
+        kept.dedup();
+        ops.extend(kept.iter().map(|&fd| FdOp::ClearCloexec { fd }));
+
+        // Status flags and offsets are shared with the parent FD, so they can only be changed once
+        // nothing will be duplicated from it any more.
+        ops.extend(mappings.iter().filter_map(|mapping| {
+            let nonblocking = match mapping.blocking_mode {
+                BlockingMode::Blocking => false,
+                BlockingMode::Nonblocking => true,
+                BlockingMode::Inherit => return None,
+            };
+            Some(FdOp::SetNonblocking {
+                fd: mapping.child_fd,
+                nonblocking,
+            })
+        }));
+        ops.extend(mappings.iter().filter_map(|mapping| {
+            Some(FdOp::Seek {
+                fd: mapping.child_fd,
+                position: mapping.seek?,
+            })
+        }));
+
+        // Moved parent FDs can only be closed once every mapping reading them has been applied.
+        // The mappings are sorted by parent FD, so each one only needs to be closed once.
+        let mut moved: Vec<RawFd> = mappings
+            .iter()
+            .filter(|mapping| {
+                mapping.move_source
+                    && child_fds.binary_search(&mapping.parent_fd).is_err()
+                    && kept.binary_search(&mapping.parent_fd).is_err()
+            })
+            .map(|mapping| mapping.parent_fd)
+            .collect();
+        moved.dedup();
+        ops.extend(moved.into_iter().map(|fd| FdOp::Close { fd }));
+
+        // Close the temporary FDs once they have all been duplicated to their final child FDs.
+        ops.extend((0..temporary_count).map(|temporary| FdOp::CloseTemporary { temporary }));
+
+        let mut plan = Self {
+            ops,
+            first_safe_fd,
+            highest_child_fd: child_fds.last().copied().unwrap_or(-1),
+            temporary_count,
+            conflicts: mappings
+                .iter()
+                .zip(&temporary_slots)
+                .filter(|(_, slot)| slot.is_some())
+                .map(|(mapping, _)| mapping.clone())
+                .collect(),
+            mappings: sorted.clone(),
+            highest_fd: -1,
+            raised_fd_limit: None,
+        };
+        plan.update_highest_fd();
+        Ok(plan)
+    }
+
+    /// Works out `highest_fd` from the child FDs and temporaries.
+    fn update_highest_fd(&mut self) {
+        // Temporaries are allocated from the lowest free FDs at or above first_safe_fd, which is
+        // higher than any child FD. Other FDs could already be open there in the child, but we
+        // can't know that from here.
+        self.highest_fd = if self.temporary_count > 0 {
+            self.first_safe_fd + self.temporary_count as RawFd - 1
+        } else {
+            self.highest_child_fd
+        };
+    }
+
+    /// Sets the first FD to use for temporaries, which must be above every child FD.
+    fn set_first_temporary_fd(&mut self, fd: RawFd) -> Result<(), FdMappingError> {
+        if fd < 0 {
+            return Err(FdMappingError::BadFd(fd));
+        }
+        if fd <= self.highest_child_fd {
+            return Err(FdMappingError::TemporaryFdTooLow {
+                fd,
+                child_fd: self.highest_child_fd,
+            });
+        }
+        self.first_safe_fd = fd;
+        self.update_highest_fd();
+        Ok(())
+    }
+
+    /// Checks that every FD the plan needs in the child is below the `RLIMIT_NOFILE` soft limit.
+    /// Otherwise dup2 or fcntl would fail with a cryptic error once the child is forked.
+    fn check_fd_limit(&self) -> Result<(), FdMappingError> {
+        if self.highest_fd < 0 {
+            return Ok(());
+        }
+        let (soft_limit, _) = nofile_limits()?;
+        if self.highest_fd as u64 >= soft_limit {
+            return Err(FdMappingError::FdTooHigh {
+                fd: self.highest_fd,
+                limit: soft_limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// Arranges for the child's `RLIMIT_NOFILE` soft limit to be raised if needed to allow every FD
+    /// the plan needs, as long as the hard limit allows it.
+    fn raise_fd_limit(&mut self) -> Result<(), FdMappingError> {
+        if self.highest_fd < 0 {
+            return Ok(());
+        }
+        let (soft_limit, hard_limit) = nofile_limits()?;
+        let needed = self.highest_fd as u64 + 1;
+        if needed <= soft_limit {
+            return Ok(());
+        }
+        if needed > hard_limit {
+            return Err(FdMappingError::HardFdLimit {
+                fd: self.highest_fd,
+                limit: hard_limit,
+            });
+        }
+        let raised = needed.saturating_add(FD_LIMIT_HEADROOM).min(hard_limit);
+        self.raised_fd_limit = Some((raised, hard_limit));
+        Ok(())
+    }
+
+    /// Applies the plan to the current process, storing temporary FDs in `temporaries`, which must
+    /// have at least `temporary_count` elements.
+    ///
+    /// If any step fails, the temporary FDs which are still open are closed before returning the
+    /// error, though any mappings which were already applied are left in place.
+    ///
+    /// This function must not do any allocation, as it is called from the pre_exec hook. If a step
+    /// fails and `failure` is given, the step and error are recorded there for the parent.
+    fn apply(
+        &self,
+        temporaries: &mut [RawFd],
+        reserved: &[OwnedFd],
+        failure: Option<&FailureSlot>,
+    ) -> Result<(), FdMappingError> {
+        if let Some(failure) = failure {
+            failure.clear();
+        }
+        if let Some((soft_limit, hard_limit)) = self.raised_fd_limit {
+            setrlimit(Resource::RLIMIT_NOFILE, soft_limit, hard_limit).map_err(io::Error::from)?;
+        }
+        let temporaries = &mut temporaries[..self.temporary_count];
+        temporaries.fill(-1);
+        let result = self
+            .apply_ops(temporaries, reserved)
+            .map_err(|(index, error)| {
+                if let Some(failure) = failure {
+                    failure.record_failure(index, error.errno());
+                }
+                error
+            });
+        if result.is_err() {
+            for temporary in temporaries.iter_mut().filter(|fd| **fd >= 0) {
+                // There is already an error to report, so ignore any from closing.
+                let _ = close(*temporary);
+                *temporary = -1;
+            }
+        }
+        result
+    }
+
+    /// Applies each step of the plan in turn, stopping at the first one which fails and returning
+    /// its index along with the error. Each slot of `temporaries` is -1 unless it holds an open
+    /// temporary FD. `reserved` holds the FDs which the parent reserved for the temporary FDs,
+    /// which may be fewer than are needed.
+    fn apply_ops(
+        &self,
+        temporaries: &mut [RawFd],
+        reserved: &[OwnedFd],
+    ) -> Result<(), (usize, FdMappingError)> {
+        for (index, op) in self.ops.iter().enumerate() {
+            self.apply_op(*op, temporaries, reserved)
+                .map_err(|error| (index, error))?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies a single step of the plan.
+    fn apply_op(
+        &self,
+        op: FdOp,
+        temporaries: &mut [RawFd],
+        reserved: &[OwnedFd],
+    ) -> Result<(), FdMappingError> {
+        match op {
+            FdOp::DupToTemporary { fd, temporary } => {
+                let reserved = match reserved.get(temporary) {
+                    Some(reserved) => dup_to_reserved(fd, reserved.as_raw_fd())
+                        .map_err(|e| FdMappingError::from_errno(e, fd))?,
+                    None => None,
+                };
+                temporaries[temporary] = match reserved {
+                    Some(reserved) => reserved,
+                    None => dup_temporary(fd, self.first_safe_fd)
+                        .map_err(|e| FdMappingError::from_errno(e, fd))?,
+                };
+            }
+            FdOp::Dup2 { from, to } => {
+                retry_on_eintr(|| dup2(from, to))
+                    .map_err(|e| FdMappingError::from_errno(e, from))?;
+            }
+            FdOp::Dup2FromTemporary { temporary, to } => {
+                let from = temporaries[temporary];
+                retry_on_eintr(|| dup2(from, to))
+                    .map_err(|e| FdMappingError::from_errno(e, from))?;
+            }
+            FdOp::ClearCloexec { fd } => {
+                retry_on_eintr(|| fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty())))
+                    .map_err(|e| FdMappingError::from_errno(e, fd))?;
+            }
+            FdOp::SetNonblocking { fd, nonblocking } => {
+                set_nonblocking_flag(fd, nonblocking)
+                    .map_err(|e| FdMappingError::from_errno(e, fd))?;
+            }
+            FdOp::Seek { fd, position } => {
+                // The checked methods reject out of range offsets up front, but fd_mappings doesn't.
+                let (offset, whence) = seek_args(position)
+                    .ok_or_else(|| FdMappingError::from_errno(Errno::EOVERFLOW, fd))?;
+                retry_on_eintr(|| lseek(fd, offset, whence))
+                    .map_err(|e| FdMappingError::from_errno(e, fd))?;
+            }
+            FdOp::Close { fd } => {
+                // As below, this mustn't be retried on EINTR.
+                close(fd).map_err(|e| FdMappingError::from_errno(e, fd))?;
+            }
+            FdOp::CloseTemporary { temporary } => {
+                let fd = temporaries[temporary];
+                temporaries[temporary] = -1;
+                // Unlike the other calls this mustn't be retried on EINTR, as the FD is closed
+                // regardless on Linux and so might have been reused by the time we retry.
+                close(fd).map_err(|e| FdMappingError::from_errno(e, fd))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn preserve_fds(fds: &[RawFd]) -> io::Result<()> {
+    FDS_PASSED.store(true, Ordering::SeqCst);
+    for fd in fds {
+        // Remove the FD_CLOEXEC flag, so the FD will be kept open when exec is called for the
+        // child.
+        retry_on_eintr(|| fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty())))?;
+    }
+
+    Ok(())
+}
+
+fn preserve_fd_range(fds: RangeInclusive<RawFd>, skip_closed: bool) -> io::Result<()> {
+    FDS_PASSED.store(true, Ordering::SeqCst);
+    for fd in fds {
+        match retry_on_eintr(|| fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))) {
+            Err(Errno::EBADF) if skip_closed => {}
+            result => {
+                result?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets `FD_CLOEXEC` on every open FD from `first_fd` upwards.
+///
+/// This function must not do any allocation, as it is called from the pre_exec hook.
+fn set_cloexec_from(first_fd: RawFd) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        // Safety: close_range doesn't access any memory of ours.
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_close_range,
+                first_fd as c_uint,
+                c_uint::MAX,
+                libc::CLOSE_RANGE_CLOEXEC,
+            )
+        };
+        if result == 0 {
+            return Ok(());
+        }
+        // Otherwise the kernel is too old to support close_range or CLOSE_RANGE_CLOEXEC, so fall
+        // back to going through the FDs one by one.
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    if set_cloexec_listed(first_fd)? {
+        return Ok(());
+    }
+
+    // Without a list of the open FDs, try every FD below the soft limit. That may be unlimited,
+    // so stop at a fixed cap rather than trying billions of FDs.
+    let (soft_limit, _) = nofile_limits()?;
+    let end =
+        RawFd::try_from(soft_limit).map_or(MAX_CHECKED_FDS, |limit| limit.min(MAX_CHECKED_FDS));
+    for fd in first_fd..end {
+        add_cloexec(fd)?;
+    }
+
+    Ok(())
+}
+
+/// Sets `FD_CLOEXEC` on every open FD from `first_fd` upwards which is listed in `/proc/self/fd`,
+/// reading the directory with `getdents64(2)` into a buffer on the stack. Returns false if the
+/// directory can't be opened, such as when `/proc` isn't mounted.
+///
+/// This function must not do any allocation, as it is called from the pre_exec hook.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn set_cloexec_listed(first_fd: RawFd) -> io::Result<bool> {
+    let path = CStr::from_bytes_with_nul(b"/proc/self/fd\0").unwrap();
+    let dir = match open(
+        path,
+        OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    ) {
+        Ok(dir) => dir,
+        Err(_) => return Ok(false),
+    };
+    let result = set_cloexec_in_dir(dir, first_fd);
+    let _ = close(dir);
+    result.map(|()| true)
+}
+
+/// Sets `FD_CLOEXEC` on every FD from `first_fd` upwards listed in the open directory `dir`,
+/// other than `dir` itself.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn set_cloexec_in_dir(dir: RawFd, first_fd: RawFd) -> io::Result<()> {
+    let mut buffer = [0u8; 1024];
+    loop {
+        // Safety: getdents64 writes at most buffer.len() bytes to the buffer.
+        let read =
+            unsafe { libc::syscall(libc::SYS_getdents64, dir, buffer.as_mut_ptr(), buffer.len()) };
+        let read = match usize::try_from(read) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(_) if Errno::last() == Errno::EINTR => continue,
+            Err(_) => return Err(io::Error::last_os_error()),
+        };
+        // Each entry is a struct linux_dirent64: an 8 byte inode number and offset, then a 2 byte
+        // record length and 1 byte type, then the NUL-terminated name.
+        let mut entries = &buffer[..read];
+        while entries.len() > 19 {
+            let length = usize::from(u16::from_ne_bytes([entries[16], entries[17]]));
+            if length <= 19 || length > entries.len() {
+                return Err(Errno::EIO.into());
+            }
+            if let Some(fd) = parse_fd(&entries[19..length]) {
+                if fd >= first_fd && fd != dir {
+                    add_cloexec(fd)?;
+                }
+            }
+            entries = &entries[length..];
+        }
+    }
+}
+
+/// Parses a NUL-terminated FD number, as listed in `/proc/self/fd`. Returns None for anything
+/// else, such as `.` and `..`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn parse_fd(name: &[u8]) -> Option<RawFd> {
+    let digits = name.split(|&byte| byte == 0).next()?;
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0 as RawFd, |fd, &byte| {
+        if !byte.is_ascii_digit() {
+            return None;
+        }
+        fd.checked_mul(10)?.checked_add(RawFd::from(byte - b'0'))
+    })
+}
+
+/// Sets `FD_CLOEXEC` on `fd` if anything is open there.
+fn add_cloexec(fd: RawFd) -> io::Result<()> {
+    match retry_on_eintr(|| fcntl(fd, FcntlArg::F_GETFD)) {
+        Ok(flags) => {
+            let flags = FdFlag::from_bits_truncate(flags) | FdFlag::FD_CLOEXEC;
+            retry_on_eintr(|| fcntl(fd, FcntlArg::F_SETFD(flags)))?;
+            Ok(())
+        }
+        // Nothing is open at this FD.
+        Err(Errno::EBADF) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Duplicates `fd` to the lowest unused FD at or above `min_fd`, with `FD_CLOEXEC` set.
+fn dup_temporary(fd: RawFd, min_fd: RawFd) -> nix::Result<RawFd> {
+    match retry_on_eintr(|| fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(min_fd))) {
+        // Some older or unusual platforms don't support F_DUPFD_CLOEXEC.
+        Err(Errno::EINVAL) => dup_temporary_fallback(fd, min_fd),
+        result => result,
+    }
+}
+
+/// Opens an FD in the parent for each temporary FD the plan needs, at or above its first temporary
+/// FD and with `FD_CLOEXEC` set, to hold the numbers which the temporary FDs will take in the child.
+/// Stops early if that fails, such as when the process is out of FDs, leaving the child to find
+/// free FDs for the rest itself.
+///
+/// Parent FDs of the plan are never reserved, even if they aren't open yet. A deferred mapping's
+/// parent FD may be opened at its number by an earlier `pre_exec` hook, possibly with
+/// `FD_CLOEXEC` set, and duplicating a temporary FD onto it would then replace the source.
+fn reserve_temporaries(plan: &MappingPlan) -> Vec<OwnedFd> {
+    let mut reserved = Vec::new();
+    if plan.temporary_count == 0 {
+        return reserved;
+    }
+    let source = match File::open("/dev/null") {
+        Ok(source) => source,
+        Err(_) => return reserved,
+    };
+    // FDs which happened to land on a parent FD, kept open until the end so they aren't picked
+    // again.
+    let mut skipped = Vec::new();
+    while reserved.len() < plan.temporary_count {
+        let fd = match dup_temporary(source.as_raw_fd(), plan.first_safe_fd) {
+            // Safety: The FD was just opened, so nothing else owns it.
+            Ok(fd) => unsafe { OwnedFd::from_raw_fd(fd) },
+            Err(_) => break,
+        };
+        if plan
+            .mappings
+            .iter()
+            .any(|mapping| mapping.parent_fd == fd.as_raw_fd())
+        {
+            skipped.push(fd);
+        } else {
+            reserved.push(fd);
+        }
+    }
+    reserved
+}
+
+/// Duplicates `fd` onto `reserved`, an FD which the parent reserved for a temporary FD, and sets
+/// `FD_CLOEXEC` on it again.
+///
+/// Returns `None` without touching it if `reserved` no longer has `FD_CLOEXEC` set, or is closed.
+/// Then something else, such as an earlier set of mappings, has already claimed that FD number in
+/// the child, so a free FD must be found instead.
+fn dup_to_reserved(fd: RawFd, reserved: RawFd) -> nix::Result<Option<RawFd>> {
+    match retry_on_eintr(|| fcntl(reserved, FcntlArg::F_GETFD)) {
+        Ok(flags) if FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC) => {}
+        _ => return Ok(None),
+    }
+    retry_on_eintr(|| dup2(fd, reserved))?;
+    retry_on_eintr(|| fcntl(reserved, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)))?;
+    Ok(Some(reserved))
+}
+
+/// Like `dup_temporary`, but sets `FD_CLOEXEC` separately rather than relying on `F_DUPFD_CLOEXEC`.
+/// This isn't atomic, but nothing else can be running in the forked child to exec in between.
+fn dup_temporary_fallback(fd: RawFd, min_fd: RawFd) -> nix::Result<RawFd> {
+    let temporary = retry_on_eintr(|| fcntl(fd, FcntlArg::F_DUPFD(min_fd)))?;
+    retry_on_eintr(|| fcntl(temporary, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)))?;
+    Ok(temporary)
+}
+
+/// Calls the given syscall wrapper until it fails with something other than `EINTR`.
+///
+/// A signal arriving in the child before it execs would otherwise fail the whole spawn. dup2 in
+/// particular isn't restarted by `SA_RESTART` in every case, so an explicit retry is needed.
+fn retry_on_eintr<T>(mut syscall: impl FnMut() -> nix::Result<T>) -> nix::Result<T> {
+    loop {
+        match syscall() {
+            Err(Errno::EINTR) => {}
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+    use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+    use std::collections::{BTreeMap, HashSet};
+    use std::ffi::OsStr;
+    use std::fs::{read_dir, read_link, File};
+    use std::io::{IoSlice, IoSliceMut, Read, Write};
+    use std::process::Output;
+    use std::str;
+    use std::sync::Once;
+    #[cfg(feature = "tokio")]
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    #[cfg(feature = "tokio")]
+    use tokio::process::Command;
+
+    static SETUP: Once = Once::new();
+    lazy_static! {
+        static ref TEST_MUTEX: Mutex<()> = Mutex::new(());
+    }
+
+    #[test]
+    fn construct_mappings() {
+        let file = File::open("testdata/file1.txt").unwrap();
+        let expected = FdMapping::new(file.as_raw_fd(), 3);
+        assert_eq!(FdMapping::new(file.as_raw_fd(), 3), expected);
+        assert_eq!(FdMapping::from((file.as_raw_fd(), 3)), expected);
+        assert_eq!(FdMapping::from_source(&file, 3), expected);
+        assert_eq!(FdMapping::try_new(file.as_raw_fd(), 3).unwrap(), expected);
+    }
+
+    #[test]
+    fn try_new_negative() {
+        assert!(matches!(
+            FdMapping::try_new(-1, 3),
+            Err(FdMappingError::BadFd(-1))
+        ));
+        assert!(matches!(
+            FdMapping::try_new(3, -1),
+            Err(FdMappingError::BadFd(-1))
+        ));
+    }
+
+    #[test]
+    fn mappings_collection() {
+        let mut mappings: FdMappings = (3..5).map(|fd| FdMapping::new(fd, fd + 10)).collect();
+        mappings.extend(vec![FdMapping::new(5, 15)]);
+        assert_eq!(mappings.len(), 3);
+        assert!(mappings.validate().is_ok());
+        assert_eq!(
+            mappings.iter().map(|m| m.child_fd).collect::<Vec<_>>(),
+            vec![13, 14, 15]
+        );
+
+        mappings.push(FdMapping::new(6, 14));
+        assert_eq!(
+            mappings.validate(),
+            Err(FdMappingCollision { child_fd: 14 })
+        );
+        assert_eq!(Vec::from(mappings).len(), 4);
+
+        let mut mappings = FdMappings::with_capacity(300);
+        let capacity = mappings.capacity();
+        assert!(capacity >= 300);
+        mappings.extend((0..300).map(|fd| FdMapping::new(fd + 10, fd + 400)));
+        assert_eq!(mappings.capacity(), capacity);
+        mappings.reserve(10);
+        assert!(mappings.capacity() >= 310);
+
+        assert_eq!(
+            FdMappings::from([FdMapping::new(3, 3)]),
+            FdMappings::from(vec![FdMapping::new(3, 3)])
+        );
+    }
+
+    #[test]
+    fn add_fd_mappings() {
+        let metrics_enabled = false;
+        let mut mappings = FdMappings::new();
+        mappings
+            .add_fd_mapping(FdMapping::new(10, 3))
+            .add_fd_mapping((11, 4));
+        if metrics_enabled {
+            mappings.add_fd_mapping((12, 5));
+        }
+        mappings.add_fd_mapping((13, 4));
+        assert_eq!(
+            mappings,
+            FdMappings::from(vec![
+                FdMapping::new(10, 3),
+                FdMapping::new(11, 4),
+                FdMapping::new(13, 4),
+            ])
+        );
+        assert_eq!(
+            std::process::Command::new("true")
+                .fd_mappings(mappings)
+                .unwrap_err(),
+            FdMappingCollision { child_fd: 4 }
+        );
+    }
+
+    #[test]
+    fn merge_mappings() {
+        let logging = FdMappings::from(vec![FdMapping::new(10, 3), FdMapping::new(11, 4)]);
+
+        let mut merged = logging.clone();
+        assert_eq!(
+            merged.merge(vec![FdMapping::new(20, 4), FdMapping::new(21, 5)]),
+            Err(FdMappingCollision { child_fd: 4 })
+        );
+        assert_eq!(merged, logging);
+        merged.merge(vec![FdMapping::new(21, 5)]).unwrap();
+        assert_eq!(merged.len(), 3);
+
+        // FD 5 is kept for the first mapping which asks for it, and 6 is the next free one.
+        let mut merged = logging.clone();
+        let renumbered = merged.merge_renumbering(
+            vec![
+                FdMapping::new(20, 4),
+                FdMapping::new(21, 5),
+                FdMapping::new(22, 5),
+            ],
+            3,
+        );
+        assert_eq!(
+            renumbered,
+            vec![
+                RenumberedMapping {
+                    requested_child_fd: 4,
+                    mapping: FdMapping::new(20, 6),
+                },
+                RenumberedMapping {
+                    requested_child_fd: 5,
+                    mapping: FdMapping::new(22, 7),
+                },
+            ]
+        );
+        assert_eq!(
+            merged,
+            FdMappings::from(vec![
+                FdMapping::new(10, 3),
+                FdMapping::new(11, 4),
+                FdMapping::new(20, 6),
+                FdMapping::new(21, 5),
+                FdMapping::new(22, 7),
+            ])
+        );
+        merged.validate().unwrap();
+    }
+
+    #[test]
+    fn planned_operations() {
+        let prepared =
+            PreparedFdMappings::new_deferred(&[FdMapping::new(3, 4), FdMapping::new(4, 3)])
+                .unwrap();
+        assert_eq!(
+            prepared.planned_fd_operations(),
+            &[
+                FdOp::DupToTemporary {
+                    fd: 4,
+                    temporary: 0
+                },
+                FdOp::Dup2 { from: 3, to: 4 },
+                FdOp::Dup2FromTemporary {
+                    temporary: 0,
+                    to: 3
+                },
+                FdOp::SetNonblocking {
+                    fd: 4,
+                    nonblocking: false
+                },
+                FdOp::SetNonblocking {
+                    fd: 3,
+                    nonblocking: false
+                },
+                FdOp::CloseTemporary { temporary: 0 },
+            ]
+        );
+        assert_eq!(prepared.first_temporary_fd(), 5);
+    }
+
+    /// Calls `f` with every permutation of `items`.
+    fn for_each_permutation<T: Clone>(items: &[T], f: &mut impl FnMut(&[T])) {
+        fn permute<T: Clone>(items: &mut Vec<T>, k: usize, f: &mut impl FnMut(&[T])) {
+            if k == items.len() {
+                f(items);
+                return;
+            }
+            for i in k..items.len() {
+                items.swap(k, i);
+                permute(items, k + 1, f);
+                items.swap(k, i);
+            }
+        }
+        permute(&mut items.to_vec(), 0, f);
+    }
+
+    #[test]
+    fn plan_independent_of_order() {
+        assert_eq!(
+            MappingPlan::new(&[FdMapping::new(3, 4), FdMapping::new(4, 5)]).unwrap(),
+            MappingPlan::new(&[FdMapping::new(4, 5), FdMapping::new(3, 4)]).unwrap()
+        );
+
+        // A cycle, a chain leading out of it, fan-out and an identity mapping.
+        let mappings = [
+            FdMapping::new(3, 4),
+            FdMapping::new(4, 5),
+            FdMapping::new(5, 3),
+            FdMapping::new(5, 6),
+            FdMapping::new(3, 7),
+            FdMapping::new(8, 8),
+        ];
+        let expected = MappingPlan::new(&mappings).unwrap();
+        assert_eq!(expected.temporary_count, 1);
+        let mut count = 0;
+        for_each_permutation(&mappings, &mut |permuted| {
+            assert_eq!(MappingPlan::new(permuted).unwrap(), expected);
+            count += 1;
+        });
+        assert_eq!(count, 720);
+    }
+
+    #[test]
+    fn plan_fan_out() {
+        let plan = MappingPlan::new(&[FdMapping::new(3, 4), FdMapping::new(3, 5)]).unwrap();
+        assert_eq!(
+            plan.ops,
+            vec![
+                FdOp::Dup2 { from: 3, to: 4 },
+                FdOp::Dup2 { from: 3, to: 5 },
+                FdOp::SetNonblocking {
+                    fd: 4,
+                    nonblocking: false
+                },
+                FdOp::SetNonblocking {
+                    fd: 5,
+                    nonblocking: false
+                },
+            ]
+        );
+        assert!(plan.conflicts.is_empty());
+
+        // The FD which the cycle saves to a temporary is only read by one mapping, so the other
+        // mappings from 3 can read it directly, as long as they come before 3 is replaced.
+        let plan = MappingPlan::new(&[
+            FdMapping::new(3, 4),
+            FdMapping::new(3, 5),
+            FdMapping::new(4, 3),
+        ])
+        .unwrap();
+        assert_eq!(
+            plan.ops,
+            vec![
+                FdOp::Dup2 { from: 3, to: 5 },
+                FdOp::DupToTemporary {
+                    fd: 4,
+                    temporary: 0
+                },
+                FdOp::Dup2 { from: 3, to: 4 },
+                FdOp::Dup2FromTemporary {
+                    temporary: 0,
+                    to: 3
+                },
+                FdOp::SetNonblocking {
+                    fd: 4,
+                    nonblocking: false
+                },
+                FdOp::SetNonblocking {
+                    fd: 5,
+                    nonblocking: false
+                },
+                FdOp::SetNonblocking {
+                    fd: 3,
+                    nonblocking: false
+                },
+                FdOp::CloseTemporary { temporary: 0 },
+            ]
+        );
+        assert_eq!(plan.conflicts, vec![FdMapping::new(4, 3)]);
+    }
+
+    #[test]
+    fn plan_move_source() {
+        // 3 is closed once both mappings have read it, but 4 is left alone, as by then it holds
+        // the FD mapped from 5.
+        let plan = MappingPlan::new(&[
+            FdMapping::new(3, 6).with_move_source(true),
+            FdMapping::new(3, 7),
+            FdMapping::new(4, 8).with_move_source(true),
+            FdMapping::new(5, 4),
+        ])
+        .unwrap();
+        assert_eq!(
+            &plan.ops[..4],
+            &[
+                FdOp::Dup2 { from: 3, to: 6 },
+                FdOp::Dup2 { from: 3, to: 7 },
+                FdOp::Dup2 { from: 4, to: 8 },
+                FdOp::Dup2 { from: 5, to: 4 },
+            ]
+        );
+        assert_eq!(plan.ops.last(), Some(&FdOp::Close { fd: 3 }));
+        assert_eq!(
+            plan.ops
+                .iter()
+                .filter(|op| matches!(op, FdOp::Close { .. }))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn plan_keep_source() {
+        // 3 is kept at its own number even though it is also moved, and 4 only needs clearing once.
+        let plan = MappingPlan::new(&[
+            FdMapping::new(3, 6)
+                .with_keep_source(true)
+                .with_move_source(true),
+            FdMapping::new(4, 7).with_keep_source(true),
+            FdMapping::new(4, 4),
+        ])
+        .unwrap();
+        assert_eq!(
+            &plan.ops[..4],
+            &[
+                FdOp::Dup2 { from: 3, to: 6 },
+                FdOp::Dup2 { from: 4, to: 7 },
+                FdOp::ClearCloexec { fd: 3 },
+                FdOp::ClearCloexec { fd: 4 },
+            ]
+        );
+        assert!(!plan.ops.iter().any(|op| matches!(op, FdOp::Close { .. })));
+
+        // Another mapping can't replace a kept parent FD.
+        assert_eq!(
+            MappingPlan::new(&[
+                FdMapping::new(3, 6).with_keep_source(true),
+                FdMapping::new(5, 3),
+            ]),
+            Err(FdMappingCollision { child_fd: 3 })
+        );
+    }
+
+    #[test]
+    fn keep_source_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        // Stdio and inherited FDs don't have FD_CLOEXEC set, so start from a file which does.
+        let file = File::open("testdata/file1.txt").unwrap();
+        let fd = file.as_raw_fd();
+        let child_fd = fd + 1;
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg(format!(
+            "dd bs=3 count=1 <&{} 2>/dev/null; dd bs=3 count=1 <&{} 2>/dev/null",
+            fd, child_fd
+        ));
+        command
+            .fd_mappings(vec![FdMapping::new(fd, child_fd).with_keep_source(true)])
+            .unwrap();
+
+        // Both FDs share an offset, so the second read carries on from the first.
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(str::from_utf8(&output.stdout).unwrap(), "test 1");
+    }
+
+    #[test]
+    fn move_source_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        // Without FD_CLOEXEC the file is inherited at its own FD as well as at 5. Duplicating it
+        // clears the flag, and keeps it clear of 5.
+        let opened = File::open("testdata/file1.txt").unwrap();
+        let fd = fcntl(opened.as_raw_fd(), FcntlArg::F_DUPFD(6)).unwrap();
+        // Safety: F_DUPFD returns a newly opened FD, which nothing else owns.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let path = std::fs::canonicalize("testdata/file1.txt").unwrap();
+        let path = path.to_str().unwrap();
+        let mut command = std::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("readlink /proc/self/fd/{} /proc/self/fd/5", fd));
+        command
+            .fd_mappings(vec![FdMapping::from_source(&file, 5)])
+            .unwrap();
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            str::from_utf8(&output.stdout).unwrap(),
+            format!("{}\n{}\n", path, path)
+        );
+
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg(format!(
+            "readlink /proc/self/fd/5; test -e /proc/self/fd/{} || echo closed",
+            fd
+        ));
+        command
+            .fd_mappings(vec![FdMapping::from_source(&file, 5).with_move_source(true)])
+            .unwrap();
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            str::from_utf8(&output.stdout).unwrap(),
+            format!("{}\nclosed\n", path)
+        );
+    }
+
+    #[test]
+    fn fan_out_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let fd1 = file1.as_raw_fd();
+        let fd2 = file2.as_raw_fd();
+        let fd = max(fd1, fd2) + 1;
+
+        // Swap the files, and also pass file1 at two more FDs.
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg(format!(
+            "readlink /proc/self/fd/{} /proc/self/fd/{} /proc/self/fd/{} /proc/self/fd/{}",
+            fd1,
+            fd2,
+            fd,
+            fd + 1
+        ));
+        command
+            .fd_mappings(vec![
+                FdMapping::new(fd1, fd2),
+                FdMapping::new(fd1, fd),
+                FdMapping::new(fd2, fd1),
+                FdMapping::new(fd1, fd + 1),
+            ])
+            .unwrap();
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        let links: Vec<&str> = str::from_utf8(&output.stdout).unwrap().lines().collect();
+        let path1 = fd_link(fd1);
+        let path2 = fd_link(fd2);
+        assert_eq!(links, vec![&path2, &path1, &path1, &path1]);
+    }
+
+    #[test]
+    fn inherited_fds() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let fd1 = file1.as_raw_fd();
+        let fd2 = file2.as_raw_fd();
+        let fd = max(fd1, fd2) + 1;
+
+        // Both files are inherited, but a mapping replaces file2, and moving file1 doesn't close it.
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg(format!(
+            "readlink /proc/self/fd/{} /proc/self/fd/{} /proc/self/fd/{}",
+            fd1, fd2, fd
+        ));
+        command
+            .inherited_fd_mappings(
+                vec![
+                    FdMapping::new(fd1, fd2),
+                    FdMapping::new(fd1, fd).with_move_source(true),
+                ],
+                vec![fd2, fd1, fd1],
+            )
+            .unwrap();
+        // The file is closed again straight away.
+        let closed_fd = File::open("testdata/file1.txt").unwrap().as_raw_fd();
+        let closed = std::process::Command::new("true")
+            .inherited_fd_mappings(vec![], vec![closed_fd])
+            .err();
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        let links: Vec<&str> = str::from_utf8(&output.stdout).unwrap().lines().collect();
+        let path1 = fd_link(fd1);
+        assert_eq!(links, vec![&path1, &path1, &path1]);
+        assert!(matches!(closed, Some(FdMappingError::BadFd(fd)) if fd == closed_fd));
+    }
+
+    #[test]
+    fn borrowed_fds() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let fd = file.as_raw_fd() + 1;
+        let mut command = std::process::Command::new("readlink");
+        command.arg(format!("/proc/self/fd/{}", fd));
+        let mut borrowed = command.borrowed_fd_mappings([(file.as_fd(), fd)]).unwrap();
+        let output = borrowed.output().unwrap();
+        drop(borrowed);
+        // The FD is no longer borrowed, so it might have been closed.
+        let released = command.output().err();
+
+        assert!(output.status.success());
+        assert_eq!(
+            str::from_utf8(&output.stdout).unwrap().trim_end(),
+            fd_link(file.as_raw_fd())
+        );
+        assert_eq!(released.unwrap().raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn apply_mappings_to_built_command() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let fd = max(file1.as_raw_fd(), file2.as_raw_fd()) + 1;
+        let mut command = Command::new("readlink");
+        command.arg(format!("/proc/self/fd/{}", fd));
+        command
+            .fd_mappings(vec![FdMapping::from_source(&file1, fd)])
+            .unwrap();
+        // These are applied after the mappings already on the command, so replace them.
+        apply_fd_mappings(&mut command, &[FdMapping::from_source(&file2, fd)]).unwrap();
+        let error = apply_fd_mappings(&mut command, &[FdMapping::new(-1, fd)]).unwrap_err();
+
+        let output = command.as_std_mut().output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            str::from_utf8(&output.stdout).unwrap().trim_end(),
+            fd_link(file2.as_raw_fd())
+        );
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn dev_null_to_child() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        // Stdout is piped by output and stdin would be a file, but the mappings replace both.
+        let mut command = std::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg("echo discarded; cat >&2")
+            .stdin(File::open("testdata/file1.txt").unwrap())
+            .dev_null_to_child(1, true)
+            .unwrap()
+            .dev_null_to_child(0, false)
+            .unwrap();
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+        assert!(output.stderr.is_empty());
+    }
+
+    #[test]
+    fn directory_to_child() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("cat");
+        command.directory_to_child("testdata", 7).unwrap();
+        // Open a file relative to the directory FD from the child, after the mappings are applied,
+        // and give it to cat as its stdin.
+        unsafe {
+            command.pre_exec(|| {
+                let fd = libc::openat(7, b"file1.txt\0".as_ptr().cast(), libc::O_RDONLY);
+                if fd < 0 || libc::dup2(fd, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, std::fs::read("testdata/file1.txt").unwrap());
+        assert!(std::process::Command::new("true")
+            .directory_to_child("testdata/file1.txt", 7)
+            .is_err());
+    }
+
+    #[test]
+    fn spawn_blocking() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let fd = file.as_raw_fd() + 1;
+
+        // No runtime is needed to spawn a tokio command this way.
+        let mut command = Command::new("readlink");
+        command
+            .arg(format!("/proc/self/fd/{}", fd))
+            .stdout(std::process::Stdio::piped());
+        let child = command
+            .spawn_blocking_with_fd_mappings(vec![FdMapping::new(file.as_raw_fd(), fd)])
+            .unwrap();
+
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            str::from_utf8(&output.stdout).unwrap().trim_end(),
+            fd_link(file.as_raw_fd())
+        );
+    }
+
+    #[test]
+    fn parent_fds_unchanged_after_spawn() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let fd1 = file1.as_raw_fd();
+        let fd2 = file2.as_raw_fd();
+        let mappings = vec![
+            FdMapping::new(fd1, fd2),
+            FdMapping::new(fd2, fd1).with_move_source(true),
+            FdMapping::new(fd1, max(fd1, fd2) + 1),
+        ];
+        let snapshot = ParentFdSnapshot::new(&mappings).unwrap();
+        let mut command = std::process::Command::new("true");
+        command.fd_mappings(mappings).unwrap();
+        assert!(command.status().unwrap().success());
+        assert!(snapshot.check().is_ok());
+
+        // Reusing an FD number for another file is noticed too.
+        drop(file2);
+        let file3 = File::open("testdata/file1.txt").unwrap();
+        assert_eq!(file3.as_raw_fd(), fd2);
+        assert!(matches!(snapshot.check(), Err(FdMappingError::BadFd(fd)) if fd == fd2));
+    }
+
+    #[test]
+    fn diagnosed_failure() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let fd = file.as_raw_fd();
+        let mut command = std::process::Command::new("true");
+        let diagnostics = command
+            .diagnosed_fd_mappings(vec![FdMapping::new(fd, 5)])
+            .unwrap();
+        drop(file);
+        assert_eq!(
+            command.status().unwrap_err().raw_os_error(),
+            Some(Errno::EBADF as i32)
+        );
+        let failure = diagnostics.take_failure().unwrap();
+        assert!(matches!(
+            failure,
+            FdMappingError::MappingFailed { parent_fd, child_fd: 5, label: None, ref source }
+                if parent_fd == fd && source.raw_os_error() == Some(Errno::EBADF as i32)
+        ));
+        assert!(failure
+            .to_string()
+            .starts_with(&format!("Failed to map FD {} to 5: ", fd)));
+        assert!(diagnostics.take_failure().is_none());
+
+        // Once the FD is open again the mappings succeed, and nothing is recorded.
+        let file = File::open("testdata/file1.txt").unwrap();
+        assert_eq!(file.as_raw_fd(), fd);
+        assert!(command.status().unwrap().success());
+        assert!(diagnostics.take_failure().is_none());
+
+        // Spawning directly reports the failing mapping too, with its label.
+        let mut command = std::process::Command::new("true");
+        unsafe {
+            command.pre_exec(move || {
+                close(fd)?;
+                Ok(())
+            });
+        }
+        let failure = command
+            .spawn_blocking_with_fd_mappings(vec![
+                FdMapping::new(fd, 6).with_label("metrics-socket")
+            ])
+            .unwrap_err();
+        assert!(matches!(
+            failure,
+            FdMappingError::MappingFailed { parent_fd, child_fd: 6, ref label, .. }
+                if parent_fd == fd && label.as_deref() == Some("metrics-socket")
+        ));
+        assert!(failure.to_string().starts_with(&format!(
+            "Failed to map 'metrics-socket' (FD {} to 6): ",
+            fd
+        )));
+    }
+
+    #[test]
+    fn op_mappings() {
+        let mappings = [
+            FdMapping::new(3, 4),
+            FdMapping::new(4, 3).with_seek(SeekPosition::Start(0)),
+            FdMapping::new(6, 5).with_move_source(true),
+            FdMapping::new(7, 7),
+        ];
+        let plan = MappingPlan::new(&mappings).unwrap();
+        assert!(plan.temporary_count > 0);
+        for index in 0..plan.ops.len() {
+            let (parent_fd, child_fd) = op_mapping(&plan.ops, index);
+            assert!(
+                mappings
+                    .iter()
+                    .any(|mapping| mapping.parent_fd == parent_fd && mapping.child_fd == child_fd),
+                "{:?} mapped to {} -> {}",
+                plan.ops[index],
+                parent_fd,
+                child_fd
+            );
+        }
+    }
+
+    #[test]
+    fn plan_simulated_exhaustively() {
+        // Every set of mappings among FDs 0 to 3, where each is either not a child FD or is mapped
+        // from one of them. This covers chains, cycles, fan-out and identity mappings.
+        for choice in 0..5i32.pow(4) {
+            let parent_fds: Vec<RawFd> = (0..4).map(|fd| choice / 5i32.pow(fd) % 5).collect();
+            for &move_source in &[false, true] {
+                let mappings: Vec<FdMapping> = (0..4)
+                    .filter(|&child_fd| parent_fds[child_fd as usize] < 4)
+                    .map(|child_fd| {
+                        FdMapping::new(parent_fds[child_fd as usize], child_fd)
+                            .with_move_source(move_source)
+                    })
+                    .collect();
+                let plan = MappingPlan::new(&mappings).unwrap();
+                // FD 4 is open but unrelated, so temporaries must skip it.
+                let mut table: FdTable = (0..5).map(|fd| (fd, (fd, true))).collect();
+                simulate(&plan, &mut table);
+
+                let mut expected: FdTable = (0..5).map(|fd| (fd, (fd, true))).collect();
+                if move_source {
+                    for mapping in &mappings {
+                        expected.remove(&mapping.parent_fd);
+                    }
+                }
+                for mapping in &mappings {
+                    expected.insert(mapping.child_fd, (mapping.parent_fd, false));
+                }
+                assert_eq!(table, expected, "{:?}", mappings);
+
+                // Each cycle needs exactly one temporary. Count them by the lowest FD on each.
+                let cycle_fds = |start: RawFd| {
+                    let mut fds = vec![start];
+                    while let Some(&parent_fd) = parent_fds.get(*fds.last().unwrap() as usize) {
+                        if parent_fd == start {
+                            return fds;
+                        }
+                        if fds.contains(&parent_fd) || fds.len() > 4 {
+                            break;
+                        }
+                        fds.push(parent_fd);
+                    }
+                    vec![]
+                };
+                let cycles = (0..4)
+                    .filter(|&fd| parent_fds[fd as usize] != fd)
+                    .filter(|&fd| cycle_fds(fd).iter().min() == Some(&fd))
+                    .count();
+                assert_eq!(plan.temporary_count, cycles, "{:?}", mappings);
+
+                // The conflicts are exactly the mappings applied from a temporary.
+                let from_temporary: Vec<FdMapping> = plan
+                    .mappings
+                    .iter()
+                    .filter(|mapping| {
+                        plan.ops.iter().any(|op| {
+                            matches!(op, FdOp::Dup2FromTemporary { to, .. } if *to == mapping.child_fd)
+                        })
+                    })
+                    .cloned()
+                    .collect();
+                assert_eq!(plan.conflicts, from_temporary, "{:?}", mappings);
+            }
+        }
+    }
+
+    #[test]
+    fn plan_chain_without_temporaries() {
+        let plan = MappingPlan::new(&[
+            FdMapping::new(3, 4),
+            FdMapping::new(4, 5),
+            FdMapping::new(5, 6),
+        ])
+        .unwrap();
+        assert_eq!(
+            plan.ops,
+            vec![
+                FdOp::Dup2 { from: 5, to: 6 },
+                FdOp::Dup2 { from: 4, to: 5 },
+                FdOp::Dup2 { from: 3, to: 4 },
+                FdOp::SetNonblocking {
+                    fd: 4,
+                    nonblocking: false
+                },
+                FdOp::SetNonblocking {
+                    fd: 5,
+                    nonblocking: false
+                },
+                FdOp::SetNonblocking {
+                    fd: 6,
+                    nonblocking: false
+                },
+            ]
+        );
+        assert_eq!(plan.temporary_count, 0);
+        assert_eq!(plan.highest_fd, 6);
+    }
+
+    #[test]
+    fn plan_cycle_with_one_temporary() {
+        let plan = MappingPlan::new(&[
+            FdMapping::new(3, 4),
+            FdMapping::new(4, 3),
+            FdMapping::new(4, 5),
+            FdMapping::new(7, 7),
+        ])
+        .unwrap();
+        assert_eq!(
+            plan.ops,
+            vec![
+                FdOp::Dup2 { from: 4, to: 5 },
+                FdOp::DupToTemporary {
+                    fd: 4,
+                    temporary: 0
+                },
+                FdOp::Dup2 { from: 3, to: 4 },
+                FdOp::Dup2FromTemporary {
+                    temporary: 0,
+                    to: 3
+                },
+                FdOp::ClearCloexec { fd: 7 },
+                FdOp::SetNonblocking {
+                    fd: 4,
+                    nonblocking: false
+                },
+                FdOp::SetNonblocking {
+                    fd: 3,
+                    nonblocking: false
+                },
+                FdOp::SetNonblocking {
+                    fd: 5,
+                    nonblocking: false
+                },
+                FdOp::SetNonblocking {
+                    fd: 7,
+                    nonblocking: false
+                },
+                FdOp::CloseTemporary { temporary: 0 },
+            ]
+        );
+        assert_eq!(plan.temporary_count, 1);
+        assert_eq!(plan.first_safe_fd, 8);
+        // 4 -> 5 is applied before 4 is replaced, so only 4 -> 3 reads the temporary.
+        assert_eq!(plan.conflicts, vec![FdMapping::new(4, 3)]);
+    }
+
+    #[test]
+    fn chained_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let fd2 = file2.as_raw_fd();
+
+        let mut command = std::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("cat <&{}; cat <&{}", fd2, fd2 + 1));
+        command
+            .fd_mappings(vec![
+                FdMapping::from_source(&file1, fd2),
+                FdMapping::new(fd2, fd2 + 1),
+            ])
+            .unwrap();
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(str::from_utf8(&output.stdout).unwrap(), "test 1test 2");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_mappings() {
+        let mappings = FdMappings::from(vec![FdMapping::new(7, 3), FdMapping::new(8, 4)]);
+        let json = serde_json::to_string(&mappings).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"parent_fd":7,"child_fd":3},{"parent_fd":8,"child_fd":4}]"#
+        );
+        assert_eq!(serde_json::from_str::<FdMappings>(&json).unwrap(), mappings);
+        assert_eq!(
+            serde_json::from_str::<FdMapping>(r#"{ "old_fd": 7, "new_fd": 3 }"#).unwrap(),
+            FdMapping::new(7, 3)
+        );
+        let labelled = FdMapping::new(7, 3).with_label("metrics-socket");
+        let json = serde_json::to_string(&labelled).unwrap();
+        assert_eq!(
+            json,
+            r#"{"parent_fd":7,"child_fd":3,"label":"metrics-socket"}"#
+        );
+        assert_eq!(serde_json::from_str::<FdMapping>(&json).unwrap(), labelled);
+        let customized = FdMapping::new(7, 3)
+            .with_blocking_mode(BlockingMode::Inherit)
+            .with_stdio_policy(StdioPolicy::Allow);
+        let json = serde_json::to_string(&customized).unwrap();
+        assert_eq!(
+            json,
+            r#"{"parent_fd":7,"child_fd":3,"blocking_mode":"inherit","stdio_policy":"allow"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<FdMapping>(&json).unwrap(),
+            customized
+        );
+    }
+
+    #[test]
+    fn validate_against_open_fds() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let fd = file.as_raw_fd();
+        // An FD which would be inherited.
+        let inherited = dup_temporary_fallback(fd, 150).unwrap();
+        fcntl(inherited, FcntlArg::F_SETFD(FdFlag::empty())).unwrap();
+        // An FD which would not be.
+        let cloexec = dup_temporary(fd, 160).unwrap();
+
+        let mappings = FdMappings::from(vec![
+            FdMapping::new(fd, inherited),
+            FdMapping::new(fd, cloexec),
+            FdMapping::new(170, 5),
+            FdMapping::new(fd, 6),
+            FdMapping::new(fd, 6),
+            FdMapping::new(fd, 1),
+        ]);
+        assert_eq!(
+            mappings.validate_against_open_fds().unwrap(),
+            vec![
+                OpenFdIssue::ReplacesInheritedFd(inherited),
+                OpenFdIssue::ParentFdNotOpen(170),
+                OpenFdIssue::DuplicateTarget(6),
+            ]
+        );
+
+        close(inherited).unwrap();
+        close(cloexec).unwrap();
+    }
+
+    #[test]
+    fn conflicting_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = Command::new("ls");
+
+        // The same mapping can't be included twice.
+        assert_eq!(
+            command
+                .fd_mappings(vec![FdMapping::new(5, 4), FdMapping::new(5, 4),])
+                .unwrap_err(),
+            FdMappingCollision { child_fd: 4 }
+        );
+
+        // Mapping two different FDs to the same FD isn't allowed either.
+        assert_eq!(
+            command
+                .fd_mappings(vec![
+                    FdMapping::new(7, 3),
+                    FdMapping::new(5, 4),
+                    FdMapping::new(6, 4),
+                ])
+                .unwrap_err(),
+            FdMappingCollision { child_fd: 4 }
+        );
+    }
+
+    #[test]
+    fn try_invalid_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = Command::new("ls");
+
+        let err = command
+            .try_fd_mappings(vec![FdMapping::new(-1, 3)])
+            .unwrap_err();
+        assert!(matches!(err, FdMappingError::BadFd(-1)));
+
+        let err = command
+            .try_fd_mappings(vec![FdMapping::new(5, 3), FdMapping::new(6, 3)])
+            .unwrap_err();
+        assert!(matches!(err, FdMappingError::DuplicateTarget(3)));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn try_one_mapping() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+
+            let file = File::open("testdata/file1.txt").unwrap();
+            command
+                .try_fd_mappings(vec![FdMapping::new(file.as_raw_fd(), 5)])
+                .unwrap();
+
+            let output = command.output().await.unwrap();
+            expect_fds(&output, &[0, 1, 2, 3, 5], 0);
+        });
+    }
+
+    #[test]
+    fn cloexec_flag() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        assert!(is_cloexec(file.as_raw_fd()).unwrap());
+        fcntl(file.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::empty())).unwrap();
+        assert!(!is_cloexec(file.as_raw_fd()).unwrap());
+        assert_eq!(
+            is_cloexec(500).unwrap_err().raw_os_error(),
+            Some(Errno::EBADF as i32)
+        );
+    }
+
+    #[test]
+    fn parse_listed_fds() {
+        assert_eq!(parse_fd(b"0\0"), Some(0));
+        assert_eq!(parse_fd(b"123\0\0\0"), Some(123));
+        assert_eq!(parse_fd(b".\0"), None);
+        assert_eq!(parse_fd(b"..\0"), None);
+        assert_eq!(parse_fd(b"\0"), None);
+        assert_eq!(parse_fd(b"99999999999\0"), None);
+    }
+
+    #[test]
+    fn set_cloexec_on_listed_fds() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let fd = fcntl(file.as_raw_fd(), FcntlArg::F_DUPFD(100)).unwrap();
+        assert!(!is_cloexec(fd).unwrap());
+        assert!(set_cloexec_listed(fd).unwrap());
+        assert!(is_cloexec(fd).unwrap());
+        close(fd).unwrap();
+    }
+
+    #[test]
+    fn max_usable_fd_follows_limit() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let (soft_limit, hard_limit) = getrlimit(Resource::RLIMIT_NOFILE).unwrap();
+        let limit = 300.min(hard_limit);
+        setrlimit(Resource::RLIMIT_NOFILE, limit, hard_limit).unwrap();
+        let max_fd = max_usable_fd();
+        let fits = max_fd.as_ref().ok().map(|&fd| {
+            std::process::Command::new("true")
+                .try_fd_mappings(vec![FdMapping::from_source(&file, fd)])
+                .is_ok()
+        });
+        setrlimit(Resource::RLIMIT_NOFILE, soft_limit, hard_limit).unwrap();
+
+        assert_eq!(max_fd.unwrap() as u64, limit - FD_LIMIT_HEADROOM - 1);
+        assert_eq!(fits, Some(true));
+    }
+
+    #[test]
+    fn fds_over_limit() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let fd1 = file1.as_raw_fd();
+        let fd2 = file2.as_raw_fd();
+        let swap = vec![FdMapping::new(fd1, fd2), FdMapping::new(fd2, fd1)];
+        let highest_temporary = max(fd1, fd2) + 1;
+
+        let (soft_limit, hard_limit) = getrlimit(Resource::RLIMIT_NOFILE).unwrap();
+        setrlimit(
+            Resource::RLIMIT_NOFILE,
+            highest_temporary as u64,
+            hard_limit,
+        )
+        .unwrap();
+
+        let mut command = Command::new("ls");
+        let too_high = command
+            .try_fd_mappings(vec![FdMapping::new(fd1, highest_temporary)])
+            .err();
+        // The swap needs a temporary above both FDs, so doesn't quite fit either.
+        let swap_too_high = command.try_fd_mappings(swap.clone()).err();
+        setrlimit(
+            Resource::RLIMIT_NOFILE,
+            highest_temporary as u64 + 1,
+            hard_limit,
+        )
+        .unwrap();
+        let swap_fits = command.try_fd_mappings(swap).is_ok();
+        setrlimit(Resource::RLIMIT_NOFILE, soft_limit, hard_limit).unwrap();
+
+        assert!(matches!(
+            too_high,
+            Some(FdMappingError::FdTooHigh { fd, .. }) if fd == highest_temporary
+        ));
+        assert!(matches!(
+            swap_too_high,
+            Some(FdMappingError::FdTooHigh { fd, .. }) if fd == highest_temporary
+        ));
+        assert!(swap_fits);
+    }
+
+    #[test]
+    fn raised_fd_limit() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let fd = file.as_raw_fd();
+        // Other tests may have left runtimes with FDs of their own open, so set the limit above the
+        // highest open FD, leaving a little room for the pipes used to spawn the child.
+        let highest_open_fd = read_dir("/proc/self/fd")
+            .unwrap()
+            .map(|entry| {
+                entry
+                    .unwrap()
+                    .file_name()
+                    .to_str()
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            })
+            .max()
+            .unwrap_or(fd);
+        let low_limit = highest_open_fd as u64 + 8;
+        let child_fd = low_limit as RawFd + 10;
+
+        let (soft_limit, hard_limit) = getrlimit(Resource::RLIMIT_NOFILE).unwrap();
+        setrlimit(Resource::RLIMIT_NOFILE, low_limit, hard_limit).unwrap();
+
+        let mut command = std::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("ulimit -n; readlink /proc/self/fd/{}", child_fd));
+        let too_high = command
+            .try_fd_mappings(vec![FdMapping::new(fd, child_fd)])
+            .err();
+        command
+            .raised_limit_fd_mappings(vec![FdMapping::new(fd, child_fd)])
+            .unwrap();
+        let output = command.output();
+        let parent_limit = getrlimit(Resource::RLIMIT_NOFILE).unwrap();
+        setrlimit(Resource::RLIMIT_NOFILE, soft_limit, hard_limit).unwrap();
+
+        assert!(matches!(
+            too_high,
+            Some(FdMappingError::FdTooHigh { fd, .. }) if fd == child_fd
+        ));
+        // Only the child's limit was raised.
+        assert_eq!(parent_limit, (low_limit, hard_limit));
+        let output = output.unwrap();
+        assert!(output.status.success());
+        let expected_limit = (child_fd as u64 + 1 + FD_LIMIT_HEADROOM).min(hard_limit);
+        assert_eq!(
+            str::from_utf8(&output.stdout).unwrap(),
+            format!("{}\n{}\n", expected_limit, fd_link(fd))
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn closed_source() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("ls");
+
+            // Nothing is open at FD 100, so the mapping is rejected straight away.
+            let err = command
+                .try_fd_mappings(vec![FdMapping::new(100, 5)])
+                .unwrap_err();
+            assert!(matches!(err, FdMappingError::BadFd(100)));
+
+            // Unless the check is deferred, in which case it can only fail once it is applied.
+            command
+                .deferred_fd_mappings(vec![FdMapping::new(100, 5)])
+                .unwrap();
+
+            let err = command.output().await.unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(Errno::EBADF as i32));
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn no_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+
+            assert!(command.fd_mappings(vec![]).is_ok());
+
+            let output = command.output().await.unwrap();
+            expect_fds(&output, &[0, 1, 2, 3], 0);
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn none_preserved() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+
+            command.preserved_fds(vec![]);
+
+            let output = command.output().await.unwrap();
+            expect_fds(&output, &[0, 1, 2, 3], 0);
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn one_mapping() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+
+            let file = File::open("testdata/file1.txt").unwrap();
+            // Map the file an otherwise unused FD.
+            assert!(command
+                .fd_mappings(vec![FdMapping::new(file.as_raw_fd(), 5),])
+                .is_ok());
+
+            let output = command.output().await.unwrap();
+            expect_fds(&output, &[0, 1, 2, 3, 5], 0);
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn one_cloned_mapping() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+
+            let file = File::open("testdata/file1.txt").unwrap();
+            command.cloned_fd_mappings([(file.as_fd(), 5)]).unwrap();
+            // The command holds its own duplicate, so closing the original doesn't matter.
+            drop(file);
+
+            let output = command.output().await.unwrap();
+            expect_fds(&output, &[0, 1, 2, 3, 5], 0);
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn cloned_mapping_to_stdin() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("cat");
+            command
+                .cloned_fd_mappings([(&File::open("testdata/file2.txt").unwrap(), 0)])
+                .unwrap();
+
+            let output = command.output().await.unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"test 2");
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn owned_pipe_closed_after_spawn() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let (mut reader, writer) = io::pipe().unwrap();
+
+            let mut command = Command::new("sh");
+            command.arg("-c").arg("echo hello >&3");
+            command.owned_fd_mappings(vec![(writer.into(), 3)]).unwrap();
+            let mut child = command.spawn().unwrap();
+            drop(command);
+            assert!(child.wait().await.unwrap().success());
+
+            // The parent's copy of the write end was closed along with the command, so reading
+            // reaches EOF once the child exits.
+            let mut output = String::new();
+            reader.read_to_string(&mut output).unwrap();
+            assert_eq!(output, "hello\n");
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn memfd() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("cat");
+            command.memfd_to_child(b"in memory", 0).unwrap();
+
+            // Each child should get to read from the start.
+            for _ in 0..2 {
+                let output = command.output().await.unwrap();
+                assert!(output.status.success());
+                assert_eq!(output.stdout, b"in memory");
+            }
+
+            // The seals stop the child writing to it.
+            let mut command = Command::new("sh");
+            command.arg("-c").arg("echo more >&3");
+            command.memfd_to_child(b"in memory", 3).unwrap();
+            let output = command.output().await.unwrap();
+            assert!(!output.status.success());
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn pipes() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg("tr a-z A-Z <&5 >&6");
+            let to_child = File::from(command.pipe_to_child(5).unwrap());
+            let mut from_child = File::from(command.pipe_from_child(6).unwrap());
+            let mut child = command.spawn().unwrap();
+            drop(command);
+
+            (&to_child).write_all(b"hello").unwrap();
+            drop(to_child);
+            let mut output = String::new();
+            from_child.read_to_string(&mut output).unwrap();
+            assert_eq!(output, "HELLO");
+            assert!(child.wait().await.unwrap().success());
+        });
+    }
+
+    #[test]
+    fn blocking_mode() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let flags = |fd| OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL).unwrap());
+        let (mut parent_end, child_end) = std::os::unix::net::UnixStream::pair().unwrap();
+        child_end.set_nonblocking(true).unwrap();
+
+        // If the child inherited O_NONBLOCK then its read would fail straight away, rather than
+        // waiting for the parent to write.
+        let mut command = std::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg("read line <&5; echo \"got $line\"")
+            .stdout(std::process::Stdio::piped());
+        command
+            .fd_mappings(vec![FdMapping::from_source(&child_end, 5)])
+            .unwrap();
+        let child = command.spawn().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        parent_end.write_all(b"hello\n").unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(str::from_utf8(&output.stdout).unwrap(), "got hello\n");
+        // The flag is shared with the parent's copy.
+        assert!(!flags(child_end.as_raw_fd()).contains(OFlag::O_NONBLOCK));
+
+        let mut command = std::process::Command::new("true");
+        command
+            .fd_mappings(vec![
+                FdMapping::from_source(&child_end, 5).with_blocking_mode(BlockingMode::Nonblocking)
+            ])
+            .unwrap();
+        assert!(command.status().unwrap().success());
+        assert!(flags(child_end.as_raw_fd()).contains(OFlag::O_NONBLOCK));
+
+        let mut command = std::process::Command::new("true");
+        command
+            .fd_mappings(vec![
+                FdMapping::from_source(&child_end, 5).with_blocking_mode(BlockingMode::Inherit)
+            ])
+            .unwrap();
+        assert!(command.status().unwrap().success());
+        assert!(flags(child_end.as_raw_fd()).contains(OFlag::O_NONBLOCK));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn blocking_tokio_socket() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            // tokio's sockets are non-blocking, but the child's copy must not be, or its read
+            // would fail with EAGAIN rather than waiting for the parent to write.
+            let (mut parent_end, child_end) = tokio::net::UnixStream::pair().unwrap();
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg("read line <&5; echo \"got $line\"")
+                .stdout(std::process::Stdio::piped());
+            command
+                .fd_mappings(vec![FdMapping::from_source(&child_end, 5)])
+                .unwrap();
+            let child = command.spawn().unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            parent_end.write_all(b"hello\n").await.unwrap();
+            let output = child.wait_with_output().await.unwrap();
+            assert!(output.status.success());
+            assert_eq!(str::from_utf8(&output.stdout).unwrap(), "got hello\n");
+        });
+    }
+
+    #[test]
+    fn concurrent_spawns_share_source() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let fd = listener.as_raw_fd();
+        let fd_flags = fcntl(fd, FcntlArg::F_GETFD).unwrap();
+        let status_flags = fcntl(fd, FcntlArg::F_GETFL).unwrap();
+        let path = fd_link(fd);
+
+        let children: Vec<_> = (0..8)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    let mut command = std::process::Command::new("readlink");
+                    command.arg("/proc/self/fd/3");
+                    command.fd_mappings(vec![FdMapping::new(fd, 3)]).unwrap();
+                    command.output().unwrap()
+                })
+            })
+            .collect();
+        for child in children {
+            let output = child.join().unwrap();
+            assert!(output.status.success());
+            assert_eq!(str::from_utf8(&output.stdout).unwrap().trim_end(), path);
+        }
+
+        // The parent's FD still has FD_CLOEXEC set, and the listener is still blocking.
+        assert_eq!(fcntl(fd, FcntlArg::F_GETFD).unwrap(), fd_flags);
+        assert_eq!(fcntl(fd, FcntlArg::F_GETFL).unwrap(), status_flags);
+    }
+
+    #[test]
+    fn eventfd_shared_counter() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("head -c 8 <&5 | od -An -tu8");
+        let event = command
+            .eventfd_to_child(0, EfdFlags::EFD_NONBLOCK, 5)
+            .unwrap();
+        nix::unistd::write(event.as_raw_fd(), &42u64.to_ne_bytes()).unwrap();
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(str::from_utf8(&output.stdout).unwrap().trim(), "42");
+        // The child read the counter, so it is zero again for the parent too.
+        assert_eq!(
+            nix::unistd::read(event.as_raw_fd(), &mut [0; 8]),
+            Err(Errno::EAGAIN)
+        );
+        let flags =
+            FdFlag::from_bits_truncate(fcntl(event.as_raw_fd(), FcntlArg::F_GETFD).unwrap());
+        assert!(flags.contains(FdFlag::FD_CLOEXEC));
+    }
+
+    #[test]
+    fn received_fd() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        // Send a non-blocking file over a socket without asking for FD_CLOEXEC on the receiving end.
+        let (sender, receiver) = std::os::unix::net::UnixStream::pair().unwrap();
+        let file = File::open("testdata/file1.txt").unwrap();
+        set_nonblocking(file.as_raw_fd(), true).unwrap();
+        let fds = [file.as_raw_fd()];
+        sendmsg::<()>(
+            sender.as_raw_fd(),
+            &[IoSlice::new(b"x")],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+        drop(file);
+        let mut buffer = [0; 1];
+        let mut iov = [IoSliceMut::new(&mut buffer)];
+        let mut cmsg_buffer = nix::cmsg_space!([RawFd; 1]);
+        let message = recvmsg::<()>(
+            receiver.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buffer),
+            MsgFlags::empty(),
+        )
+        .unwrap();
+        let received = match message.cmsgs().next() {
+            Some(ControlMessageOwned::ScmRights(fds)) => fds[0],
+            other => panic!("Unexpected control message {:?}", other),
+        };
+        // Safety: the FD was just received, so nothing else owns it.
+        let received = unsafe { OwnedFd::from_raw_fd(received) };
+        let raw_received = received.as_raw_fd();
+
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("cat <&5");
+        command.received_fd_to_child(received, 5, false).unwrap();
+        let flags = FdFlag::from_bits_truncate(fcntl(raw_received, FcntlArg::F_GETFD).unwrap());
+        assert!(flags.contains(FdFlag::FD_CLOEXEC));
+        let flags = OFlag::from_bits_truncate(fcntl(raw_received, FcntlArg::F_GETFL).unwrap());
+        assert!(!flags.contains(OFlag::O_NONBLOCK));
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(str::from_utf8(&output.stdout).unwrap(), "test 1");
+    }
+
+    #[test]
+    fn seek_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        // Reset to the start, after the parent has read part of the file.
+        let mut file = File::open("testdata/file1.txt").unwrap();
+        file.read_exact(&mut [0; 5]).unwrap();
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("cat <&3");
+        command
+            .fd_mappings(vec![
+                FdMapping::from_source(&file, 3).with_seek(SeekPosition::Start(0))
+            ])
+            .unwrap();
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(str::from_utf8(&output.stdout).unwrap(), "test 1");
+        // The child read the whole file through the shared offset.
+        assert_eq!(lseek(file.as_raw_fd(), 0, Whence::SeekCur).unwrap(), 6);
+
+        // Seek to the end, so the child appends to a file which the parent has rewound.
+        let log =
+            std::env::temp_dir().join(format!("tokio-command-fds-{}.seek", std::process::id()));
+        let mut log_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&log)
+            .unwrap();
+        log_file.write_all(b"first\n").unwrap();
+        lseek(log_file.as_raw_fd(), 0, Whence::SeekSet).unwrap();
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("echo second >&4");
+        command
+            .fd_mappings(vec![
+                FdMapping::from_source(&log_file, 4).with_seek(SeekPosition::End(0))
+            ])
+            .unwrap();
+        assert!(command.status().unwrap().success());
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "first\nsecond\n");
+        std::fs::remove_file(&log).unwrap();
+
+        // An offset which doesn't fit in an off_t is rejected rather than wrapping around, up front
+        // by the checked methods and when spawning otherwise.
+        let too_far = SeekPosition::Start(u64::MAX);
+        let mut command = std::process::Command::new("true");
+        assert!(matches!(
+            command.try_fd_mappings(vec![FdMapping::from_source(&file, 3).with_seek(too_far)]),
+            Err(FdMappingError::SeekOutOfRange {
+                child_fd: 3,
+                position: SeekPosition::Start(u64::MAX),
+            })
+        ));
+        command
+            .fd_mappings(vec![FdMapping::from_source(&file, 3).with_seek(too_far)])
+            .unwrap();
+        let err = command.status().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(Errno::EOVERFLOW as i32));
+    }
+
+    #[test]
+    fn spawn_twice() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("cat <&3; cat <&4");
+        command
+            .file_to_child("testdata/file1.txt", OpenOptions::new().read(true), 4)
+            .unwrap();
+        command.memfd_to_child(b"memfd ", 3).unwrap();
+
+        // Both children get the same FDs, as the mappings are applied for each spawn. The file's
+        // offset is shared between them though, so the second child reads nothing from it.
+        let first = command.output().unwrap();
+        let second = command.output().unwrap();
+        assert!(first.status.success());
+        assert!(second.status.success());
+        assert_eq!(str::from_utf8(&first.stdout).unwrap(), "memfd test 1");
+        assert_eq!(str::from_utf8(&second.stdout).unwrap(), "memfd ");
+    }
+
+    #[test]
+    fn cleared_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let mut command = std::process::Command::new("ls");
+        command.arg("/proc/self/fd");
+        command
+            .try_fd_mappings(vec![FdMapping::new(file1.as_raw_fd(), 5)])
+            .unwrap();
+        let handle = command
+            .clearable_fd_mappings(vec![FdMapping::new(file2.as_raw_fd(), 6)])
+            .unwrap();
+
+        let output = command.output().unwrap();
+        expect_fds(&output, &[0, 1, 2, 5, 6], 1);
+
+        handle.clear_fd_mappings();
+        assert!(handle.is_cleared());
+        let output = command.output().unwrap();
+        expect_fds(&output, &[0, 1, 2, 5], 1);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn testing_passed_fds() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let inherited = dup_temporary(file.as_raw_fd(), 120).unwrap();
+        fcntl(inherited, FcntlArg::F_SETFD(FdFlag::empty())).unwrap();
+
+        let mut command = crate::testing::fd_listing_command();
+        command
+            .fd_mappings(vec![FdMapping::from_source(&file, 5)])
+            .unwrap();
+        assert_eq!(
+            crate::testing::passed_fds(&mut command).unwrap(),
+            vec![0, 1, 2, 5, inherited]
+        );
+
+        let mut command = crate::testing::fd_listing_command();
+        command
+            .close_other_fds()
+            .fd_mappings(vec![FdMapping::from_source(&file, 5)])
+            .unwrap();
+        assert_eq!(
+            crate::testing::passed_fds(&mut command).unwrap(),
+            vec![0, 1, 2, 5]
+        );
+        close(inherited).unwrap();
+    }
+
+    #[test]
+    fn files() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let log =
+            std::env::temp_dir().join(format!("tokio-command-fds-{}.log", std::process::id()));
+        std::fs::write(&log, "first\n").unwrap();
+
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("cat <&3; echo second >&4");
+        command
+            .file_to_child("testdata/file1.txt", OpenOptions::new().read(true), 3)
+            .unwrap()
+            .file_to_child(&log, OpenOptions::new().append(true), 4)
+            .unwrap();
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(str::from_utf8(&output.stdout).unwrap(), "test 1");
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "first\nsecond\n");
+        std::fs::remove_file(&log).unwrap();
+
+        let mut command = std::process::Command::new("true");
+        let err = command
+            .file_to_child("testdata/missing.txt", OpenOptions::new().read(true), 3)
+            .unwrap_err();
+        assert!(matches!(err, FdMappingError::Syscall(e) if e.kind() == io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn listeners() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("ls");
+        command.arg("/proc/self/fd");
+
+        let tcp_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        tcp_listener.set_nonblocking(true).unwrap();
+        let tcp_fd = tcp_listener.as_raw_fd();
+        let dir = std::env::temp_dir().join(format!("tokio-command-fds-{}", std::process::id()));
+        let unix_listener = std::os::unix::net::UnixListener::bind(&dir).unwrap();
+        std::fs::remove_file(&dir).unwrap();
+        let unix_fd = unix_listener.as_raw_fd();
+        command
+            .listener_to_child(tcp_listener, 5, false)
+            .unwrap()
+            .listener_to_child(unix_listener, 6, true)
+            .unwrap();
+
+        // The command still owns the listeners, so their FDs are still open here.
+        let flags = |fd| OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL).unwrap());
+        assert!(!flags(tcp_fd).contains(OFlag::O_NONBLOCK));
+        assert!(flags(unix_fd).contains(OFlag::O_NONBLOCK));
+
+        let output = command.output().unwrap();
+        expect_fds(&output, &[0, 1, 2, 3, 5, 6], 0);
+    }
+
+    #[test]
+    fn udp_socket() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let address = socket.local_addr().unwrap();
+        let mut command = std::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg("dd bs=64 count=1 <&3 2>/dev/null")
+            .stdout(std::process::Stdio::piped());
+        command.udp_socket_to_child(socket, 3).unwrap();
+        let child = command.spawn().unwrap();
+
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"datagram", address).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(str::from_utf8(&output.stdout).unwrap(), "datagram");
+    }
+
+    #[cfg(feature = "socket2")]
+    #[test]
+    fn socket2_socket() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let socket =
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None).unwrap();
+        socket.set_reuse_address(true).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let address: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        socket.bind(&address.into()).unwrap();
+        let address = socket.local_addr().unwrap().as_socket().unwrap();
+        let mut command = std::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg("dd bs=64 count=1 <&3 2>/dev/null")
+            .stdout(std::process::Stdio::piped());
+        command.socket2_to_child(socket, 3, false).unwrap();
+        let child = command.spawn().unwrap();
+
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"datagram", address).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(str::from_utf8(&output.stdout).unwrap(), "datagram");
+    }
+
+    #[test]
+    fn guarded_pipe_closed_after_spawn() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let (read_end, write_end) = cloexec_pipe().unwrap();
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("echo hello >&6");
+        let passed = command.guarded_fd_mappings(vec![(write_end, 6)]).unwrap();
+        let mut child = command.spawn().unwrap();
+        passed.close();
+
+        // Only the child has the write end now, even though the command is still around.
+        let mut output = String::new();
+        File::from(read_end).read_to_string(&mut output).unwrap();
+        assert_eq!(output, "hello\n");
+        assert!(child.wait().unwrap().success());
+
+        let err = command.spawn().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(Errno::EBADF as i32));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn socketpair() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg("read line <&7; echo \"got $line\" >&7");
+            let mut stream = command.socketpair_to_child(7).unwrap();
+            let mut child = command.spawn().unwrap();
+            drop(command);
+
+            stream.write_all(b"hello\n").await.unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).await.unwrap();
+            assert_eq!(reply, "got hello\n");
+            assert!(child.wait().await.unwrap().success());
+        });
+    }
+
+    #[test]
+    fn retried_spawn() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        // The program doesn't exist until after the first attempt to spawn it.
+        let shell =
+            std::env::temp_dir().join(format!("tokio-command-fds-{}.sh", std::process::id()));
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        // A swap, which needs a temporary FD in the child.
+        let fd1 = fcntl(file1.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(20)).unwrap();
+        let fd2 = fcntl(file2.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(fd1 + 1)).unwrap();
+        let mut command = std::process::Command::new(&shell);
+        command.arg("-c").arg(format!(
+            "cat /dev/fd/{}; echo; cat /dev/fd/{}; echo; cat <&3; echo; cat <&4",
+            fd1, fd2
+        ));
+        command
+            .try_fd_mappings(vec![
+                FdMapping::new(fd1, fd2),
+                FdMapping::new(fd2, fd1).with_move_source(true),
+            ])
+            .unwrap();
+        let owned = OwnedFd::from(File::open("testdata/file1.txt").unwrap());
+        let owned_fd = owned.as_raw_fd();
+        command.owned_fd_mappings(vec![(owned, 3)]).unwrap();
+        let guarded = OwnedFd::from(File::open("testdata/file2.txt").unwrap());
+        let guarded_fd = guarded.as_raw_fd();
+        let passed = command.guarded_fd_mappings(vec![(guarded, 4)]).unwrap();
+
+        let err = command.output().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        // The failed spawn didn't close or move anything in the parent.
+        for fd in [fd1, fd2, owned_fd, guarded_fd] {
+            assert!(is_cloexec(fd).unwrap());
+        }
+
+        std::os::unix::fs::symlink("/bin/sh", &shell).unwrap();
+        let output = command.output();
+        std::fs::remove_file(&shell).unwrap();
+        let output = output.unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            str::from_utf8(&output.stdout).unwrap(),
+            "test 2\ntest 1\ntest 1\ntest 2"
+        );
+        drop(passed);
+        close(fd1).unwrap();
+        close(fd2).unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn log_pipe() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg("echo '{\"level\":\"info\"}' >&5; echo not a log; echo done >&5")
+                .stdout(std::process::Stdio::piped());
+            let (receiver, passed) = command.log_pipe_from_child(5).unwrap();
+            let child = command.spawn().unwrap();
+            // The command is still around, but the lines below only end once the child exits.
+            passed.close();
+
+            let mut lines = BufReader::new(receiver).lines();
+            let mut logged = Vec::new();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                logged.push(line);
+            }
+            assert_eq!(logged, vec![r#"{"level":"info"}"#, "done"]);
+            let output = child.wait_with_output().await.unwrap();
+            assert!(output.status.success());
+            assert_eq!(str::from_utf8(&output.stdout).unwrap(), "not a log\n");
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn tokio_socket_to_child() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let (mut parent_end, child_end) = tokio::net::UnixStream::pair().unwrap();
+            let child_end = take_raw_fd_for_child(child_end, false).unwrap();
+            let flags =
+                OFlag::from_bits_truncate(fcntl(child_end.as_raw_fd(), FcntlArg::F_GETFL).unwrap());
+            assert!(!flags.contains(OFlag::O_NONBLOCK));
+
+            let mut command = Command::new("sh");
+            command.arg("-c").arg("echo hello >&5");
+            command.owned_fd_mappings(vec![(child_end, 5)]).unwrap();
+            let mut child = command.spawn().unwrap();
+            drop(command);
+
+            let mut output = String::new();
+            parent_end.read_to_string(&mut output).await.unwrap();
+            assert_eq!(output, "hello\n");
+            assert!(child.wait().await.unwrap().success());
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn one_preserved() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+
+            let file = File::open("testdata/file1.txt").unwrap();
+            let file_fd = file.as_raw_fd();
+            command.preserved_fds(vec![file_fd]);
+            assert!(file_fd > 3);
+
+            let output = command.output().await.unwrap();
+            expect_fds(&output, &[0, 1, 2, 3, file_fd], 0);
+        })
+    }
+
+    #[test]
+    fn preserved_range() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        // Put the files well clear of any other FDs, with a gap after them.
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let fd1 = dup_temporary(file1.as_raw_fd(), 100).unwrap();
+        let fd2 = dup_temporary(file2.as_raw_fd(), 101).unwrap();
+        assert_eq!((fd1, fd2), (100, 101));
+        let range = 100..=102;
+
+        let mut command = std::process::Command::new("ls");
+        command.arg("/proc/self/fd");
+        command.preserved_fd_range(range.clone(), true);
+        let output = command.output().unwrap();
+        // Expect one more FD for the /proc/self/fd directory.
+        expect_fds(&output, &[0, 1, 2, fd1, fd2], 1);
+
+        let mut command = std::process::Command::new("ls");
+        command.preserved_fd_range(range, false);
+        let err = command.output().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(Errno::EBADF as i32));
+
+        close(fd1).unwrap();
+        close(fd2).unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn preserved_and_mapped() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+
+            let file1 = File::open("testdata/file1.txt").unwrap();
+            let file2 = File::open("testdata/file2.txt").unwrap();
+            let file3 = File::open("testdata/file1.txt").unwrap();
+            let fd1 = file1.as_raw_fd();
+            let fd2 = file2.as_raw_fd();
+            let fd3 = file3.as_raw_fd();
+            // Swapping fd1 and fd2 needs temporaries, which mustn't land on fd3 even though it is
+            // the first FD above those in the mappings. fd1 is also preserved as a parent FD.
+            command.preserved_fds(vec![fd1, fd3]);
+            command
+                .fd_mappings(vec![FdMapping::new(fd1, fd2), FdMapping::new(fd2, fd1)])
+                .unwrap();
+
+            let output = command.output().await.unwrap();
+            expect_fds(&output, &[0, 1, 2, fd1, fd2, fd3], 1);
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn close_other_fds() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let file1 = File::open("testdata/file1.txt").unwrap();
+            let file2 = File::open("testdata/file2.txt").unwrap();
+            // Unlike files opened by std, this doesn't have FD_CLOEXEC set so would normally leak
+            // into the child.
+            let leaked = OwnedFd::from(file1.try_clone().unwrap());
+            fcntl(leaked.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::empty())).unwrap();
+
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+            let output = command.output().await.unwrap();
+            expect_fds(&output, &[0, 1, 2, 3, leaked.as_raw_fd()], 0);
+
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+            command.close_other_fds();
+            command.preserved_fds(vec![file2.as_raw_fd()]);
+            command
+                .fd_mappings(vec![FdMapping::new(file1.as_raw_fd(), 10)])
+                .unwrap();
+            let output = command.output().await.unwrap();
+            expect_fds(&output, &[0, 1, 2, file2.as_raw_fd(), 10], 1);
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn close_other_fds_too_late() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let file = File::open("testdata/file1.txt").unwrap();
+
+            let mut command = Command::new("ls");
+            command
+                .fd_mappings(vec![FdMapping::new(file.as_raw_fd(), 10)])
+                .unwrap();
+            command.close_other_fds();
+
+            let err = command.output().await.unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(Errno::EINVAL as i32));
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn compact_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let file1 = File::open("testdata/file1.txt").unwrap();
+            let file2 = File::open("testdata/file2.txt").unwrap();
+            let fd1 = file1.as_raw_fd();
+            let fd2 = file2.as_raw_fd();
+
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+            let mappings = command.compact_fd_mappings(vec![fd2, fd1], 10).unwrap();
+            assert_eq!(
+                mappings,
+                vec![FdMapping::new(fd2, 10), FdMapping::new(fd1, 11),]
+            );
+
+            let output = command.output().await.unwrap();
+            expect_fds(&output, &[0, 1, 2, 3, 10, 11], 0);
+        });
+    }
+
+    #[test]
+    fn sanitized_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let mut command = std::process::Command::new("ls");
+        command.arg("/proc/self/fd");
+        command.close_other_fds();
+        let mappings = command
+            .sanitized_fd_mappings(vec![
+                FdMapping::from_source(&file2, 50),
+                FdMapping::from_source(&file1, 1).with_seek(SeekPosition::Start(0)),
+                FdMapping::new(0, 9),
+            ])
+            .unwrap();
+        assert_eq!(
+            mappings,
+            vec![
+                FdMapping::from_source(&file2, 3),
+                FdMapping::from_source(&file1, 4).with_seek(SeekPosition::Start(0)),
+                FdMapping::new(0, 5),
+            ]
+        );
+
+        // Stdout is still the pipe, and 6 is the FD which ls opens to read the directory.
+        let output = command.output().unwrap();
+        expect_fds(&output, &[0, 1, 2, 3, 4, 5, 6], 0);
+    }
+
+    #[test]
+    fn clamped_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let mappings = vec![
+            FdMapping::from_source(&file1, 100),
+            FdMapping::from_source(&file2, 4).with_label("kept"),
+            FdMapping::from_source(&file1, 200),
+        ];
+        let too_small = std::process::Command::new("true")
+            .clamped_fd_mappings(mappings.clone(), 3..5)
+            .err();
+        let mut command = std::process::Command::new("ls");
+        command.arg("/proc/self/fd");
+        let clamped = command.clamped_fd_mappings(mappings, 3..6).unwrap();
+
+        assert!(matches!(
+            too_small,
+            Some(FdMappingError::WindowTooSmall {
+                count: 3,
+                start: 3,
+                end: 5
+            })
+        ));
+        assert_eq!(
+            clamped,
+            vec![
+                FdMapping::from_source(&file1, 3),
+                FdMapping::from_source(&file2, 4).with_label("kept"),
+                FdMapping::from_source(&file1, 5),
+            ]
+        );
+        // The FD which ls opens to read the directory comes after the window.
+        expect_fds(&command.output().unwrap(), &[0, 1, 2, 3, 4, 5], 1);
+    }
+
+    #[test]
+    fn assign_fds() {
+        assert_eq!(
+            assign_child_fds(&[(10, None), (11, Some(4)), (12, None), (13, Some(3))], 3),
+            vec![
+                FdMapping::new(10, 5),
+                FdMapping::new(11, 4),
+                FdMapping::new(12, 6),
+                FdMapping::new(13, 3),
+            ]
+        );
+        assert_eq!(
+            assign_child_fds(&[(10, None), (11, None)], 7),
+            vec![FdMapping::new(10, 7), FdMapping::new(11, 8)]
+        );
+        assert_eq!(
+            assign_child_fds(&[(10, Some(3)), (11, Some(3))], 3),
+            vec![FdMapping::new(10, 3), FdMapping::new(11, 3)]
+        );
+    }
+
+    #[test]
+    fn assigned_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("ls");
+        command.arg("/proc/self/fd");
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let mappings = command
+            .assigned_fd_mappings(
+                vec![(file1.as_raw_fd(), None), (file2.as_raw_fd(), Some(6))],
+                6,
+            )
+            .unwrap();
+        assert_eq!(
+            mappings,
+            vec![
+                FdMapping::new(file1.as_raw_fd(), 7),
+                FdMapping::new(file2.as_raw_fd(), 6),
+            ]
+        );
+
+        let output = command.output().unwrap();
+        // Expect one more FD for the /proc/self/fd directory, which might be 3 or above 7.
+        expect_fds(&output, &[0, 1, 2, 6, 7], 1);
+    }
+
+    #[test]
+    fn advertised_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg("cat <&$DATA_FD; echo; cat <&$CONTROL_FD");
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let mappings = command
+            .advertised_fd_mappings(
+                vec![
+                    ("DATA_FD", file1.as_raw_fd(), None),
+                    ("CONTROL_FD", file2.as_raw_fd(), Some(3)),
+                ],
+                3,
+            )
+            .unwrap();
+        assert_eq!(mappings[0].child_fd, 4);
+        let envs: Vec<_> = command.get_envs().collect();
+        assert!(envs.contains(&(OsStr::new("DATA_FD"), Some(OsStr::new("4")))));
+        assert!(envs.contains(&(OsStr::new("CONTROL_FD"), Some(OsStr::new("3")))));
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"test 1\ntest 2");
+
+        // Nothing is advertised if the mappings can't be added.
+        let mut command = std::process::Command::new("true");
+        command
+            .advertised_fd_mappings(vec![("DATA_FD", -1, None)], 3)
+            .unwrap_err();
+        assert_eq!(command.get_envs().count(), 0);
+    }
+
+    #[test]
+    fn clear_activation_env() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg("echo ${LISTEN_FDS-unset} ${LISTEN_PID-unset} ${NOTIFY_SOCKET-unset}");
+        command.env("LISTEN_PID", "1");
+        command
+            .socket_activation_fds(vec![])
+            .unwrap()
+            .clear_activation_env();
+
+        let envs: Vec<_> = command.get_envs().collect();
+        assert!(envs.contains(&(OsStr::new("NOTIFY_SOCKET"), None)));
+        assert!(envs.contains(&(OsStr::new("LISTEN_FDNAMES"), None)));
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(str::from_utf8(&output.stdout).unwrap(), "0 1 unset\n");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn socket_activation() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let pipes: Vec<_> = (0..3).map(|_| io::pipe().unwrap()).collect();
+            // Pass them in reverse order, so that some of them need to move out of each other's way.
+            let fds: Vec<RawFd> = pipes
+                .iter()
+                .rev()
+                .map(|(reader, _)| reader.as_raw_fd())
+                .collect();
+            let expected: Vec<String> = fds.iter().map(|&fd| fd_link(fd)).collect();
+
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(
+                "i=3; while [ $i -lt $((3 + LISTEN_FDS)) ]; do readlink /proc/self/fd/$i; \
+                 i=$((i + 1)); done",
+            );
+            command.socket_activation_fds(fds).unwrap();
+
+            let output = command.output().await.unwrap();
+            assert!(output.status.success());
+            let links: Vec<&str> = str::from_utf8(&output.stdout).unwrap().lines().collect();
+            assert_eq!(links, expected);
+        });
+    }
+
+    #[test]
+    fn socket_activation_listen_pid() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let mut command = listen_pid_command("sh");
+        command
+            .arg("-c")
+            .arg("echo $LISTEN_PID $$ $LISTEN_FDS")
+            .stdout(std::process::Stdio::piped());
+        command
+            .socket_activation_fds(vec![file.as_raw_fd()])
+            .unwrap();
+
+        let child = command.spawn().unwrap();
+        let pid = child.id();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            str::from_utf8(&output.stdout).unwrap(),
+            format!("{} {} 1\n", pid, pid)
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn socket_activated_listeners() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let (mut command, expected) = basic_rt.block_on(async {
+            let mut listeners = Vec::new();
+            for _ in 0..2 {
+                listeners.push(tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap());
+            }
+            let expected: Vec<String> = listeners
+                .iter()
+                .map(|listener| fd_link(listener.as_raw_fd()))
+                .collect();
+            (socket_activated_command("sh", listeners).unwrap(), expected)
+        });
+        command
+            .arg("-c")
+            .arg("echo $LISTEN_PID $$ $LISTEN_FDS; readlink /proc/self/fd/3 /proc/self/fd/4")
+            .stdout(std::process::Stdio::piped());
+
+        let child = command.spawn().unwrap();
+        let pid = child.id();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        let stdout = str::from_utf8(&output.stdout).unwrap();
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines[0], format!("{} {} 2", pid, pid));
+        assert_eq!(&lines[1..], &expected[..]);
+    }
+
+    #[test]
+    fn socket_activation_with_other_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let fd1 = file1.as_raw_fd();
+        let fd2 = file2.as_raw_fd();
+
+        let mut command = std::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg("echo $LISTEN_FDS; readlink /proc/self/fd/3 /proc/self/fd/4 /proc/self/fd/10");
+        // FD 4 is the second activation FD.
+        let overlapping = command
+            .socket_activation_fds_with(vec![fd1, fd2], vec![FdMapping::new(fd1, 4)])
+            .err();
+        assert!(matches!(
+            overlapping,
+            Some(FdMappingError::DuplicateTarget(4))
+        ));
+
+        command
+            .socket_activation_fds_with(vec![fd1, fd2], vec![FdMapping::new(fd1, 10)])
+            .unwrap();
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            str::from_utf8(&output.stdout).unwrap(),
+            format!("2\n{}\n{}\n{}\n", fd_link(fd1), fd_link(fd2), fd_link(fd1))
+        );
+    }
+
+    #[test]
+    fn reserved_temporaries_with_exhausted_fds() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        // Keep the mappings well above the lowest free FDs, which spawning itself needs for pipes.
+        let source1 =
+            unsafe { OwnedFd::from_raw_fd(dup_temporary(file1.as_raw_fd(), 100).unwrap()) };
+        let source2 =
+            unsafe { OwnedFd::from_raw_fd(dup_temporary(file2.as_raw_fd(), 100).unwrap()) };
+        let (fd1, fd2) = (source1.as_raw_fd(), source2.as_raw_fd());
+        let first_temporary_fd = max(fd1, fd2) + 1;
+
+        let (soft_limit, hard_limit) = getrlimit(Resource::RLIMIT_NOFILE).unwrap();
+        setrlimit(Resource::RLIMIT_NOFILE, 200.min(hard_limit), hard_limit).unwrap();
+        let mut command = std::process::Command::new("ls");
+        command.arg("/proc/self/fd");
+        command
+            .try_fd_mappings(vec![FdMapping::new(fd1, fd2), FdMapping::new(fd2, fd1)])
+            .unwrap();
+        // Use up every FD from the temporary FD onwards, as another thread might have done.
+        let mut filler = Vec::new();
+        let exhausted = loop {
+            match dup_temporary(file1.as_raw_fd(), first_temporary_fd) {
+                Ok(fd) => filler.push(unsafe { OwnedFd::from_raw_fd(fd) }),
+                Err(e) => break e,
+            }
+        };
+        let output = command.output();
+        drop(filler);
+        setrlimit(Resource::RLIMIT_NOFILE, soft_limit, hard_limit).unwrap();
+
+        assert_eq!(exhausted, Errno::EMFILE);
+        expect_fds(&output.unwrap(), &[0, 1, 2, fd1, fd2], 1);
+    }
+
+    #[test]
+    fn reserved_temporaries_avoid_deferred_parent_fds() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let source1 =
+            unsafe { OwnedFd::from_raw_fd(dup_temporary(file1.as_raw_fd(), 100).unwrap()) };
+        let source2 =
+            unsafe { OwnedFd::from_raw_fd(dup_temporary(file2.as_raw_fd(), 100).unwrap()) };
+        let (fd1, fd2) = (source1.as_raw_fd(), source2.as_raw_fd());
+        // FD 200 isn't open yet, and is the lowest free FD for temporaries, but must not be reserved.
+        let deferred_fd = 200;
+        let child_fd = max(fd1, fd2) + 1;
+        let prepared = PreparedFdMappings::new_deferred(&[
+            FdMapping::new(fd1, fd2),
+            FdMapping::new(fd2, fd1),
+            FdMapping::new(deferred_fd, child_fd).with_move_source(true),
+        ])
+        .unwrap()
+        .with_first_temporary_fd(deferred_fd)
+        .unwrap();
+        assert_eq!(prepared.reserved.len(), 1);
+        assert!(prepared.reserved[0].as_raw_fd() > deferred_fd);
+
+        // An earlier hook opens the deferred parent FD with FD_CLOEXEC set, which a reservation at
+        // the same number couldn't tell apart from itself. The temporary FD would then be closed
+        // along with the moved source, and closing it again would fail.
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg(format!(
+            "readlink /proc/self/fd/{} /proc/self/fd/{} /proc/self/fd/{}",
+            fd1, fd2, child_fd
+        ));
+        let source = file1.as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                if libc::dup3(source, deferred_fd, libc::O_CLOEXEC) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut prepared = prepared;
+        unsafe {
+            command.pre_exec(move || prepared.map_fds());
+        }
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        let links: Vec<&str> = str::from_utf8(&output.stdout).unwrap().lines().collect();
+        let path1 = fd_link(fd1);
+        let path2 = fd_link(fd2);
+        assert_eq!(links, vec![&path2, &path1, &path1]);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn swap_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+
+            let file1 = File::open("testdata/file1.txt").unwrap();
+            let file2 = File::open("testdata/file2.txt").unwrap();
+            let fd1 = file1.as_raw_fd();
+            let fd2 = file2.as_raw_fd();
+            // Map files to each other's FDs, to ensure that the temporary FD logic works.
+            assert!(command
+                .fd_mappings(vec![FdMapping::new(fd1, fd2), FdMapping::new(fd2, fd1),])
+                .is_ok(),);
+
+            let output = command.output().await.unwrap();
+            // Expect one more Fd for the /proc/self/fd directory. We can't predict what number it will
+            // be assigned, because 3 might or might not be taken already by fd1 or fd2.
+            expect_fds(&output, &[0, 1, 2, fd1, fd2], 1);
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn swap_mappings_twice() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+
+            let file1 = File::open("testdata/file1.txt").unwrap();
+            let file2 = File::open("testdata/file2.txt").unwrap();
+            let fd1 = file1.as_raw_fd();
+            let fd2 = file2.as_raw_fd();
+            command
+                .fd_mappings(vec![FdMapping::new(fd1, fd2), FdMapping::new(fd2, fd1)])
+                .unwrap();
+
+            // The plan is applied afresh in each child, so running the command again should give
+            // the same result.
+            for _ in 0..2 {
+                let output = command.output().await.unwrap();
+                expect_fds(&output, &[0, 1, 2, fd1, fd2], 1);
+            }
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn rotate_many_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            // Each pipe has a distinct inode, so the child can tell which one it got at each FD.
+            let pipes: Vec<_> = (0..100).map(|_| io::pipe().unwrap()).collect();
+            let fds: Vec<RawFd> = pipes.iter().map(|(reader, _)| reader.as_raw_fd()).collect();
+
+            // Rotate every FD onto the next one, so every mapping conflicts with another.
+            let mappings: Vec<FdMapping> = fds
+                .iter()
+                .zip(fds.iter().cycle().skip(1))
+                .map(|(&parent_fd, &child_fd)| FdMapping::new(parent_fd, child_fd))
+                .collect();
+            let expected: Vec<String> = mappings
+                .iter()
+                .map(|mapping| fd_link(mapping.parent_fd))
+                .collect();
+
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg("for fd in \"$@\"; do readlink /proc/self/fd/$fd; done")
+                .arg("sh")
+                .args(mappings.iter().map(|mapping| mapping.child_fd.to_string()));
+            command.fd_mappings(mappings).unwrap();
+
+            let output = command.output().await.unwrap();
+            assert!(output.status.success());
+            let links: Vec<&str> = str::from_utf8(&output.stdout).unwrap().lines().collect();
+            assert_eq!(links, expected);
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn one_to_one_mapping() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("ls");
+            command.arg("/proc/self/fd");
+
+            let file1 = File::open("testdata/file1.txt").unwrap();
+            let file2 = File::open("testdata/file2.txt").unwrap();
+            let fd1 = file1.as_raw_fd();
+            // Map file1 to the same FD it currently has, to ensure the special case for that works.
+            assert!(command.fd_mappings(vec![FdMapping::new(fd1, fd1)]).is_ok());
+
+            let output = command.output().await.unwrap();
+            // Expect one more Fd for the /proc/self/fd directory. We can't predict what number it will
+            // be assigned, because 3 might or might not be taken already by fd1 or fd2.
+            expect_fds(&output, &[0, 1, 2, fd1], 1);
+
+            // Keep file2 open until the end, to ensure that it's not passed to the child.
+            drop(file2);
+        });
+    }
+
+    #[test]
+    fn identity_mapping_clears_cloexec() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let fd = file.as_raw_fd();
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).unwrap();
+
+        // dup2 to the same FD would leave FD_CLOEXEC set, so it must be cleared explicitly instead.
+        let mapping = FdMapping::new(fd, fd);
+        assert_eq!(
+            MappingPlan::new(std::slice::from_ref(&mapping))
+                .unwrap()
+                .ops,
+            vec![
+                FdOp::ClearCloexec { fd },
+                FdOp::SetNonblocking {
+                    fd,
+                    nonblocking: false
+                }
+            ]
+        );
+
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg(format!("cat <&{}", fd));
+        command.fd_mappings(vec![mapping]).unwrap();
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(str::from_utf8(&output.stdout).unwrap(), "test 1");
+        // Only the child's copy is changed.
+        let flags = FdFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFD).unwrap());
+        assert!(flags.contains(FdFlag::FD_CLOEXEC));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn map_stdin() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let mut command = Command::new("cat");
+
+            let file = File::open("testdata/file1.txt").unwrap();
+            // Map the file to stdin.
+            assert!(command
+                .fd_mappings(vec![FdMapping::new(file.as_raw_fd(), 0),])
+                .is_ok());
+
+            let output = command.output().await.unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"test 1");
+        });
+    }
+
+    #[test]
+    fn stdio_policy() {
+        let file = File::open("testdata/file1.txt").unwrap();
+        let mut command = std::process::Command::new("cat");
+        assert!(matches!(
+            command.try_fd_mappings(vec![FdMapping::from_source(&file, 1)]),
+            Err(FdMappingError::StdioTarget(1))
+        ));
+        // Mapping a stdio FD to itself, or from one, leaves the configuration alone.
+        command
+            .try_fd_mappings(vec![FdMapping::new(0, 0), FdMapping::new(2, 5)])
+            .unwrap();
+        for policy in [StdioPolicy::Allow, StdioPolicy::Warn] {
+            command
+                .try_fd_mappings(vec![
+                    FdMapping::from_source(&file, 1).with_stdio_policy(policy)
+                ])
+                .unwrap();
+        }
+        // Unchecked mappings are allowed regardless.
+        command
+            .fd_mappings(vec![FdMapping::from_source(&file, 2)])
+            .unwrap();
+    }
+
+    #[test]
+    fn parent_stdio_to_child() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        // The child's own stdio is all pipes, but it still gets this process's stdio at 3 to 5.
+        let mut command = std::process::Command::new("readlink");
+        command
+            .args(["/proc/self/fd/3", "/proc/self/fd/4", "/proc/self/fd/5"])
+            .stdin(std::process::Stdio::piped());
+        command
+            .parent_stdin_to_child(3)
+            .unwrap()
+            .parent_stdout_to_child(4)
+            .unwrap()
+            .parent_stderr_to_child(5)
+            .unwrap();
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        let links: Vec<&str> = str::from_utf8(&output.stdout).unwrap().lines().collect();
+        assert_eq!(links, vec![fd_link(0), fd_link(1), fd_link(2)]);
+    }
+
+    #[test]
+    fn map_stdout_to_pipe() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("echo out; echo err >&2");
+        let (read_end, write_end) = cloexec_pipe().unwrap();
+        // Stderr goes to what stdout was configured as, which output() sets to a pipe of its own.
+        command
+            .fd_mappings(vec![
+                FdMapping::from_source(&write_end, 1),
+                FdMapping::new(1, 2),
+            ])
+            .unwrap();
+        let output = command.output().unwrap();
+        drop(write_end);
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"err\n");
+        assert_eq!(output.stderr, b"");
+        let mut stdout = String::new();
+        File::from(read_end).read_to_string(&mut stdout).unwrap();
+        assert_eq!(stdout, "out\n");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn piped_stdout_with_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let file = File::open("testdata/file1.txt").unwrap();
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg("cat <&3 >&5; echo ' done'")
+                .stdout(std::process::Stdio::piped());
+            // FD 1 refers to tokio's pipe by the time the mappings are applied.
+            command
+                .fd_mappings(vec![FdMapping::from_source(&file, 3), FdMapping::new(1, 5)])
+                .unwrap();
+
+            let output = command.output().await.unwrap();
+            assert!(output.status.success());
+            assert_eq!(str::from_utf8(&output.stdout).unwrap(), "test 1 done\n");
+        });
+    }
+
+    #[test]
+    fn swap_stdout_and_stderr() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg("echo out; echo err >&2");
+        command
+            .fd_mappings(vec![FdMapping::new(1, 2), FdMapping::new(2, 1)])
+            .unwrap();
+        let output = command.output().unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"err\n");
+        assert_eq!(output.stderr, b"out\n");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn chained_spawn() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+        let basic_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        basic_rt.block_on(async {
+            let file = File::open("testdata/file1.txt").unwrap();
+            // The mappings should be usable inline in a builder chain straight into `spawn`.
+            let child = Command::new("cat")
+                .fd_mappings(vec![FdMapping::new(file.as_raw_fd(), 0)])
+                .unwrap()
+                .stdout(std::process::Stdio::piped())
+                .spawn()
+                .unwrap();
+
+            let output = child.wait_with_output().await.unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"test 1");
+        });
+    }
+
+    #[test]
+    fn std_one_mapping() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("ls");
+        command.arg("/proc/self/fd");
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        command
+            .fd_mappings(vec![FdMapping::new(file.as_raw_fd(), 5)])
+            .unwrap();
+
+        let output = command.output().unwrap();
+        expect_fds(&output, &[0, 1, 2, 3, 5], 0);
+    }
+
+    #[test]
+    fn prepared_mappings_in_own_hook() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("ls");
+        command.arg("/proc/self/fd");
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let mut prepared = PreparedFdMappings::new(&[FdMapping::new(file.as_raw_fd(), 5)]).unwrap();
+        unsafe {
+            command.pre_exec(move || {
+                nix::unistd::setsid()?;
+                prepared.map_fds()
+            });
+        }
+
+        let output = command.output().unwrap();
+        expect_fds(&output, &[0, 1, 2, 3, 5], 0);
+    }
+
+    #[test]
+    fn mappings_with_own_hooks() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let path = fd_link(file.as_raw_fd());
+        for setsid_first in [true, false] {
+            let mut command = std::process::Command::new("sh");
+            command
+                .arg("-c")
+                .arg("readlink /proc/self/fd/5; exec cat /proc/self/stat")
+                .stdout(std::process::Stdio::piped());
+            let add_setsid = |command: &mut std::process::Command| unsafe {
+                command.pre_exec(|| {
+                    nix::unistd::setsid()?;
+                    Ok(())
+                });
+            };
+            if setsid_first {
+                add_setsid(&mut command);
+            }
+            command
+                .try_fd_mappings(vec![FdMapping::from_source(&file, 5)])
+                .unwrap();
+            if !setsid_first {
+                add_setsid(&mut command);
+            }
+
+            let child = command.spawn().unwrap();
+            let pid = child.id();
+            let output = child.wait_with_output().unwrap();
+            assert!(output.status.success());
+            let stdout = str::from_utf8(&output.stdout).unwrap();
+            let (link, stat) = stdout.split_once('\n').unwrap();
+            assert_eq!(link, path);
+            // The fields after the command name are the state, parent PID, process group and
+            // session, which is the child's own PID if setsid ran.
+            let fields: Vec<&str> = stat
+                .rsplit_once(')')
+                .unwrap()
+                .1
+                .split_whitespace()
+                .collect();
+            assert_eq!(fields[3], pid.to_string());
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn mappings_with_process_group() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("readlink /proc/self/fd/5; exec cat /proc/self/stat")
+            .stdout(std::process::Stdio::piped());
+        command
+            .try_fd_mappings(vec![FdMapping::from_source(&file, 5)])
+            .unwrap();
+        // Set after the mappings, but it is still in place by the time the hooks run.
+        command.process_group(0);
+        unsafe {
+            command.pre_exec(|| {
+                if nix::unistd::getpgrp() != nix::unistd::getpid() {
+                    return Err(Errno::EPERM.into());
+                }
+                Ok(())
+            });
+        }
+
+        let child = command.as_std_mut().spawn().unwrap();
+        let pid = child.id();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        let stdout = str::from_utf8(&output.stdout).unwrap();
+        let (link, stat) = stdout.split_once('\n').unwrap();
+        assert_eq!(link, fd_link(file.as_raw_fd()));
+        // The process group comes after the state and parent PID.
+        let fields: Vec<&str> = stat
+            .rsplit_once(')')
+            .unwrap()
+            .1
+            .split_whitespace()
+            .collect();
+        assert_eq!(fields[2], pid.to_string());
+    }
+
+    #[test]
+    fn prepared_mappings_invalid() {
+        assert!(matches!(
+            PreparedFdMappings::new(&[FdMapping::new(3, 5), FdMapping::new(4, 5)]),
+            Err(FdMappingError::DuplicateTarget(5))
+        ));
+    }
+
+    #[test]
+    fn prepared_mappings_first_temporary_fd() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let fd1 = file1.as_raw_fd();
+        let fd2 = file2.as_raw_fd();
+        let high = dup_temporary(fd1, 400).unwrap();
+        let mappings = [
+            FdMapping::new(fd1, fd2),
+            FdMapping::new(fd2, fd1),
+            FdMapping::new(high, fd2 + 1),
+        ];
+
+        let prepared = PreparedFdMappings::new(&mappings).unwrap();
+        assert_eq!(prepared.conflicts(), &[FdMapping::new(fd2, fd1)]);
+        assert_eq!(prepared.first_temporary_fd(), high + 1);
+        assert!(matches!(
+            prepared.clone().with_first_temporary_fd(fd2 + 1),
+            Err(FdMappingError::TemporaryFdTooLow { fd, child_fd }) if fd == fd2 + 1 && child_fd == fd2 + 1
+        ));
+        // Temporaries may start below the parent FDs, as those are never reused.
+        let mut prepared = prepared.with_first_temporary_fd(fd2 + 2).unwrap();
+        assert_eq!(prepared.first_temporary_fd(), fd2 + 2);
+
+        let mut command = std::process::Command::new("readlink");
+        for fd in [fd1, fd2, fd2 + 1] {
+            command.arg(format!("/proc/self/fd/{}", fd));
+        }
+        unsafe {
+            command.pre_exec(move || prepared.map_fds());
+        }
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        let links: Vec<&str> = str::from_utf8(&output.stdout).unwrap().lines().collect();
+        let path1 = fd_link(fd1);
+        let path2 = fd_link(fd2);
+        assert_eq!(links, vec![&path2, &path1, &path1]);
+        close(high).unwrap();
+    }
+
+    #[test]
+    fn repeated_mappings_applied_in_order() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        // Use child FDs which are clear of the files, whichever FDs they got.
+        let fd = max(file1.as_raw_fd(), file2.as_raw_fd()) + 1;
+
+        let mut command = std::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("cat <&{}; cat <&{}", fd, fd + 1));
+        command
+            .fd_mappings(vec![FdMapping::from_source(&file1, fd)])
+            .unwrap();
+        // The first FD here refers to what the first call mapped there, and is then replaced.
+        command
+            .fd_mappings(vec![
+                FdMapping::new(fd, fd + 1),
+                FdMapping::from_source(&file2, fd),
+            ])
+            .unwrap();
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(str::from_utf8(&output.stdout).unwrap(), "test 2test 1");
+    }
+
+    #[test]
+    fn converted_to_async_std() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("ls");
+        command.arg("/proc/self/fd");
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        command
+            .fd_mappings(vec![FdMapping::from_source(&file, 5)])
+            .unwrap();
+
+        let mut command = async_std::process::Command::from(command);
+        let output = async_std::task::block_on(command.output()).unwrap();
+        expect_fds(&output, &[0, 1, 2, 3, 5], 0);
+    }
+
+    #[test]
+    fn std_swap_mappings() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let mut command = std::process::Command::new("ls");
+        command.arg("/proc/self/fd");
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let fd1 = file1.as_raw_fd();
+        let fd2 = file2.as_raw_fd();
+        command
+            .fd_mappings(vec![FdMapping::new(fd1, fd2), FdMapping::new(fd2, fd1)])
+            .unwrap();
+
+        let output = command.output().unwrap();
+        expect_fds(&output, &[0, 1, 2, fd1, fd2], 1);
+    }
+
+    #[test]
+    fn sparse_high_fds() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file1 = File::open("testdata/file1.txt").unwrap();
+        let file2 = File::open("testdata/file2.txt").unwrap();
+        let fd1 = file1.as_raw_fd();
+        // The swap needs a temporary from 601 upwards, where the parent already has an FD open
+        // which the child inherits.
+        let high = dup_temporary(file2.as_raw_fd(), 600).unwrap();
+        let inherited = dup_temporary(fd1, 601).unwrap();
+        assert_eq!((high, inherited), (600, 601));
+        fcntl(inherited, FcntlArg::F_SETFD(FdFlag::empty())).unwrap();
+
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg(format!(
+            "readlink /proc/self/fd/{} /proc/self/fd/600 /proc/self/fd/601; ls /proc/self/fd",
+            fd1
+        ));
+        command
+            .fd_mappings(vec![FdMapping::new(fd1, 600), FdMapping::new(600, fd1)])
+            .unwrap();
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        let stdout = str::from_utf8(&output.stdout).unwrap();
+        let mut lines = stdout.lines();
+        let path1 = fd_link(fd1);
+        let path2 = fd_link(high);
+        assert_eq!(
+            lines.by_ref().take(3).collect::<Vec<_>>(),
+            vec![&path2, &path1, &path1]
+        );
+        // No temporary is left behind above the inherited FD.
+        assert!(lines.all(|fd| fd.parse::<RawFd>().unwrap() <= 601));
+
+        // The parent's FDs are untouched.
+        assert_eq!(fd_link(fd1), path1);
+        assert_eq!(fd_link(600), path2);
+        assert_eq!(fd_link(601), path1);
+        close(high).unwrap();
+        close(inherited).unwrap();
+    }
+
+    #[test]
+    fn temporaries_closed_on_failure() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        // Two swaps, the second of which fails as nothing is open at FD 203, after the first has
+        // already used a temporary FD.
+        let file = File::open("testdata/file1.txt").unwrap();
+        let fd1 = dup_temporary(file.as_raw_fd(), 200).unwrap();
+        let fd2 = dup_temporary(file.as_raw_fd(), 201).unwrap();
+        let fd3 = dup_temporary(file.as_raw_fd(), 202).unwrap();
+        assert_eq!((fd1, fd2, fd3), (200, 201, 202));
+        let plan = MappingPlan::new(&[
+            FdMapping::new(200, 201),
+            FdMapping::new(201, 200),
+            FdMapping::new(202, 203),
+            FdMapping::new(203, 202),
+        ])
+        .unwrap();
+        assert_eq!(plan.temporary_count, 2);
+
+        let mut temporaries = vec![-1; plan.temporary_count];
+        assert!(matches!(
+            plan.apply(&mut temporaries, &[], None),
+            Err(FdMappingError::BadFd(203))
+        ));
+        assert_eq!(temporaries, vec![-1, -1]);
+        // The first temporary would have been the lowest free FD above all the mappings.
+        assert_eq!(
+            fcntl(plan.first_safe_fd, FcntlArg::F_GETFD),
+            Err(Errno::EBADF)
+        );
+
+        for fd in [fd1, fd2, fd3] {
+            close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn dup_temporaries() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        setup();
+
+        let file = File::open("testdata/file1.txt").unwrap();
+        for dup in [dup_temporary, dup_temporary_fallback] {
+            let temporary = dup(file.as_raw_fd(), 20).unwrap();
+            assert!(temporary >= 20);
+            let flags = FdFlag::from_bits_truncate(fcntl(temporary, FcntlArg::F_GETFD).unwrap());
+            assert!(flags.contains(FdFlag::FD_CLOEXEC));
+            assert_eq!(fd_link(temporary), fd_link(file.as_raw_fd()));
+            close(temporary).unwrap();
+        }
+    }
+
+    #[test]
+    fn retry_interrupted_syscall() {
+        let mut attempts = 0;
+        let result = retry_on_eintr(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(Errno::EINTR)
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result, Ok(3));
+
+        // Other errors are returned straight away.
+        attempts = 0;
+        let result: nix::Result<()> = retry_on_eintr(|| {
+            attempts += 1;
+            Err(Errno::EBADF)
+        });
+        assert_eq!(result, Err(Errno::EBADF));
+        assert_eq!(attempts, 1);
+    }
+
+    /// A model of an FD table, mapping each open FD to an ID for the file it refers to and whether it
+    /// has `FD_CLOEXEC` set.
+    type FdTable = BTreeMap<RawFd, (RawFd, bool)>;
+
+    /// Applies the steps of a plan to a model FD table, as `MappingPlan::apply` does to the real
+    /// one, panicking at any step which would fail. Temporary FDs are taken from the lowest unused
+    /// FDs from the plan's first temporary FD, like `dup_temporary` does.
+    fn simulate(plan: &MappingPlan, table: &mut FdTable) {
+        let mut temporaries = vec![-1; plan.temporary_count];
+        for op in &plan.ops {
+            match *op {
+                FdOp::DupToTemporary { fd, temporary } => {
+                    let file = table[&fd].0;
+                    let free = (plan.first_safe_fd..)
+                        .find(|fd| !table.contains_key(fd))
+                        .unwrap();
+                    table.insert(free, (file, true));
+                    temporaries[temporary] = free;
+                }
+                FdOp::Dup2 { from, to } => {
+                    let file = table[&from].0;
+                    table.insert(to, (file, false));
+                }
+                FdOp::Dup2FromTemporary { temporary, to } => {
+                    let file = table[&temporaries[temporary]].0;
+                    table.insert(to, (file, false));
+                }
+                FdOp::ClearCloexec { fd } => table.get_mut(&fd).unwrap().1 = false,
+                FdOp::SetNonblocking { fd, .. } | FdOp::Seek { fd, .. } => {
+                    assert!(table.contains_key(&fd), "FD {} isn't open", fd);
+                }
+                FdOp::Close { fd } => {
+                    table.remove(&fd).unwrap();
+                }
+                FdOp::CloseTemporary { temporary } => {
+                    table.remove(&temporaries[temporary]).unwrap();
+                    temporaries[temporary] = -1;
+                }
+            }
+        }
+        assert!(temporaries.iter().all(|&fd| fd == -1));
+    }
+
+    /// Returns what the given FD in this process refers to, e.g. `pipe:[1234]`.
+    fn fd_link(fd: RawFd) -> String {
+        read_link(format!("/proc/self/fd/{}", fd))
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    /// Parse the output of ls into a set of filenames
+    fn parse_ls_output(output: &[u8]) -> HashSet<String> {
+        str::from_utf8(output)
+            .unwrap()
+            .split_terminator("\n")
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Check that the output of `ls /proc/self/fd` contains the expected set of FDs, plus exactly
+    /// `extra` extra FDs.
+    fn expect_fds(output: &Output, expected_fds: &[RawFd], extra: usize) {
+        assert!(output.status.success());
+        let expected_fds: HashSet<String> = expected_fds.iter().map(RawFd::to_string).collect();
+        let fds = parse_ls_output(&output.stdout);
+        if extra == 0 {
+            assert_eq!(fds, expected_fds);
+        } else {
+            assert!(expected_fds.is_subset(&fds));
+            assert_eq!(fds.len(), expected_fds.len() + extra);
+        }
+    }
+
+    fn setup() {
+        SETUP.call_once(close_excess_fds);
+    }
+
+    /// Close all file descriptors apart from stdin, stdout and stderr.
+    ///
+    /// This is necessary because GitHub Actions opens a bunch of others for some reason.
+    fn close_excess_fds() {
+        let dir = read_dir("/proc/self/fd").unwrap();
+        for entry in dir {
+            let entry = entry.unwrap();
+            let fd: RawFd = entry.file_name().to_str().unwrap().parse().unwrap();
+            if fd > 3 {
+                close(fd).unwrap();
+            }
+        }
+    }
+}